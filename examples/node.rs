@@ -0,0 +1,104 @@
+//! Minimal node embedding the networking layer: it gossips peer lists with its peers and keeps
+//! the controller informed of what happens on the connections.
+//!
+//! Run two nodes that know each other:
+//!
+//! ```text
+//! cargo run --example node -- 4001 127.0.0.1:4002
+//! cargo run --example node -- 4002
+//! ```
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::{info, warn};
+use test_massa::codec::{self, MAX_FRAME_SIZE};
+use test_massa::{
+    BoxedConnection, ChannelMessage, NetworkConfig, NetworkController, NetworkControllerEvent,
+    NetworkEvent,
+};
+
+/// Delay between two peer list requests to a peer
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(30);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut args = std::env::args().skip(1);
+    let port: u16 = args.next().ok_or("usage: node <port> [peer...]")?.parse()?;
+    let bootstrap: Vec<String> = args.collect();
+    let peers_file = std::env::temp_dir().join(format!("example-node-{}.json", port));
+    if !peers_file.exists() {
+        std::fs::write(&peers_file, serde_json::to_string(&bootstrap)?)?;
+    }
+    let config = NetworkConfig::builder()
+        .peers_file(peers_file.to_str().ok_or("non UTF-8 temp dir")?)
+        .listen_ip("127.0.0.1".parse()?)
+        .listen_port(port)
+        .build()?;
+
+    let net = NetworkController::new(config).await?;
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            event = net.wait_event() => match event? {
+                NetworkControllerEvent::CandidateConnection { addr, socket, .. } => {
+                    tokio::spawn(serve_peer(net.clone(), addr, socket));
+                }
+                NetworkControllerEvent::Event(NetworkEvent::PeerAlive { addr, is_outgoing }) => {
+                    info!("{addr} is alive (outgoing: {is_outgoing})");
+                }
+                NetworkControllerEvent::Event(NetworkEvent::PeersLearned { addrs }) => {
+                    info!("Learned {addrs:?}")
+                }
+                _ => {}
+            },
+        }
+    }
+
+    net.shutdown().await?;
+    Ok(())
+}
+
+/// Exchange peer lists with a connected peer until the connection ends, then report how it
+/// ended to the controller.
+async fn serve_peer(net: NetworkController, addr: SocketAddr, mut socket: BoxedConnection) {
+    let closed = match exchange(&net, &mut socket).await {
+        Ok(()) => net.feedback_peer_closed(&addr).await,
+        Err(err) => {
+            warn!("Connection with {addr} failed: {err}");
+            net.feedback_peer_failed(&addr).await
+        }
+    };
+    if let Err(err) = closed {
+        warn!("{err}");
+    }
+}
+
+async fn exchange(
+    net: &NetworkController,
+    socket: &mut BoxedConnection,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = socket.peer_addr()?;
+    let mut gossip = tokio::time::interval(GOSSIP_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            _ = gossip.tick() => {
+                codec::write_message(socket, &ChannelMessage::AskPeersList).await?;
+                continue;
+            }
+            message = codec::read_message(socket, MAX_FRAME_SIZE) => message?,
+        };
+        match message {
+            ChannelMessage::AskPeersList => {
+                let list = ChannelMessage::PeersList(net.get_good_peer_ips().await?);
+                codec::write_message(socket, &list).await?;
+            }
+            ChannelMessage::PeersList(addrs) => net.feedback_peer_list(addrs).await?,
+            ChannelMessage::Alive => info!("{addr} is still there"),
+            ChannelMessage::Close => return Ok(()),
+            message => warn!("Unexpected message from {addr}: {message:?}"),
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use test_massa::network::config::NetworkConfig;
+use test_massa::network::controller::NetworkControllerError;
+use test_massa::network::file::{PeersFileController, PeersFileControllerError};
+use test_massa::network::peer::{self, Peer, PeerError};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Massa test network node")]
+pub struct Cli {
+    /// TOML or JSON config file, MASSA_NETWORK_* environment variables override it
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Peers file, overrides the config
+    #[arg(long, global = true)]
+    pub peers_file: Option<String>,
+    /// Log filter such as `info` or `test_massa=debug`, overrides RUST_LOG
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+    /// Runs the node when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the node
+    Run {
+        /// Address to accept connections on, overrides the config
+        #[arg(long, value_name = "IP:PORT")]
+        listen: Option<SocketAddr>,
+    },
+    /// Inspect or edit the peers file, while the node is stopped
+    #[command(subcommand)]
+    Peers(PeersCommand),
+    /// Inspect the config
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PeersCommand {
+    /// List the known peers
+    List,
+    /// Add a peer, from `ip:port` or a bare `ip` listening on our port
+    Add { addr: String },
+    /// Ban a peer, adding it if unknown
+    Ban { addr: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Validate the config and print it with the overrides applied
+    Check,
+}
+
+impl Cli {
+    /// Config from the config file and the environment, with the command line overrides,
+    /// validated once they are all applied.
+    pub fn network_config(&self) -> Result<NetworkConfig, NetworkControllerError> {
+        let mut config = NetworkConfig::load(self.config.as_deref())?;
+        if let Some(peers_file) = &self.peers_file {
+            config.peers_file = peers_file.clone();
+        }
+        if let Some(Command::Run {
+            listen: Some(listen),
+        }) = &self.command
+        {
+            config.listen_ip = listen.ip();
+            config.listen_port = listen.port();
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+pub async fn peers(
+    command: &PeersCommand,
+    config: &NetworkConfig,
+) -> Result<(), NetworkControllerError> {
+    let file_controller = PeersFileController::new(&config.peers_file, config.listen_port);
+    let mut peers = match file_controller.read_file() {
+        Ok(peers) => peers,
+        // Adding the first peer creates the file
+        Err(PeersFileControllerError::Io(err))
+            if err.kind() == io::ErrorKind::NotFound && !matches!(command, PeersCommand::List) =>
+        {
+            HashMap::new()
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    match command {
+        PeersCommand::List => {
+            let mut peers: Vec<&Peer> = peers.values().collect();
+            peers.sort_by_key(|peer| *peer.addr());
+            for peer in peers {
+                println!(
+                    "{}\t{:?}\tlast alive: {}\tlast failure: {}{}",
+                    peer.addr(),
+                    peer.status(),
+                    peer.last_alive()
+                        .map_or("never".to_string(), |date| date.to_rfc3339()),
+                    peer.last_failure()
+                        .map_or("never".to_string(), |date| date.to_rfc3339()),
+                    if peer.is_self() { "\t(self)" } else { "" }
+                );
+            }
+            return Ok(());
+        }
+        PeersCommand::Add { addr } => {
+            let addr = parse_addr(addr, config)?;
+            peers.entry(addr).or_insert_with(|| Peer::from_addr(addr));
+        }
+        PeersCommand::Ban { addr } => {
+            let addr = parse_addr(addr, config)?;
+            peers
+                .entry(addr)
+                .or_insert_with(|| Peer::from_addr(addr))
+                .banned(Utc::now());
+        }
+    }
+
+    file_controller.changed();
+    file_controller.write_file(&peers).await?;
+
+    Ok(())
+}
+
+fn parse_addr(addr: &str, config: &NetworkConfig) -> Result<SocketAddr, PeerError> {
+    Ok(peer::parse_addr(addr, config.listen_port)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use tempfile::TempDir;
+    use test_massa::network::peer::PeerStatus;
+
+    /// Config whose peers file lives in `dir`
+    fn config(dir: &TempDir) -> NetworkConfig {
+        let peers_file = dir.path().join("peers.json");
+        NetworkConfig::builder()
+            .peers_file(peers_file.to_str().unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn read_peers(config: &NetworkConfig) -> HashMap<SocketAddr, Peer> {
+        PeersFileController::new(&config.peers_file, config.listen_port)
+            .read_file()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from([
+            "test-massa",
+            "run",
+            "--listen",
+            "127.0.0.1:8081",
+            "--peers-file",
+            "node1.json",
+        ]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Run { listen: Some(_) })
+        ));
+        assert_eq!(Some("node1.json".to_string()), cli.peers_file);
+
+        let cli = Cli::parse_from(["test-massa", "peers", "ban", "10.0.0.1:8080"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Peers(PeersCommand::Ban { addr })) if addr == "10.0.0.1:8080"
+        ));
+    }
+
+    #[test]
+    fn test_network_config_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+        std::fs::write(&config_file, "listen_port = 0\n").unwrap();
+        let config_path = config_file.to_str().unwrap();
+
+        // The command line fixes the invalid port of the config file
+        let cli = Cli::parse_from(["test-massa", "--config", config_path, "run"]);
+        assert!(cli.network_config().is_err());
+        let cli = Cli::parse_from([
+            "test-massa",
+            "--config",
+            config_path,
+            "run",
+            "--listen",
+            "127.0.0.1:8081",
+        ]);
+        assert_eq!(8081, cli.network_config().unwrap().listen_port);
+    }
+
+    #[tokio::test]
+    async fn test_peers_add_and_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        let add = |addr: &str| PeersCommand::Add {
+            addr: addr.to_string(),
+        };
+
+        // Adding the first peer creates the file
+        peers(&add("10.0.0.1"), &config).await.unwrap();
+        peers(&add("10.0.0.2:9090"), &config).await.unwrap();
+        peers(&add("10.0.0.1:8080"), &config).await.unwrap();
+        peers(&PeersCommand::List, &config).await.unwrap();
+
+        let mut known: Vec<SocketAddr> = read_peers(&config).into_keys().collect();
+        known.sort();
+        assert_eq!(
+            vec![
+                "10.0.0.1:8080".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:9090".parse().unwrap()
+            ],
+            known
+        );
+        assert!(matches!(
+            peers(&add("not an address"), &config).await,
+            Err(NetworkControllerError::PeerError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_peers_ban_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+        std::fs::write(&config.peers_file, "[\"10.0.0.1\"]").unwrap();
+
+        peers(
+            &PeersCommand::Ban {
+                addr: "10.0.0.2".to_string(),
+            },
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let known = read_peers(&config);
+        let banned: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+        assert_eq!(2, known.len());
+        assert_eq!(PeerStatus::Banned, known[&banned].status());
+        assert!(known[&banned].last_failure().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_peers_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir);
+
+        // Listing doesn't create the file
+        assert!(matches!(
+            peers(&PeersCommand::List, &config).await,
+            Err(NetworkControllerError::FileController(
+                PeersFileControllerError::Io(err)
+            )) if err.kind() == io::ErrorKind::NotFound
+        ));
+        assert!(!std::path::Path::new(&config.peers_file).exists());
+    }
+}
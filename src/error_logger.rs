@@ -14,7 +14,7 @@ impl<T, Error> InspectErr<Error> for Result<T, Error> {
 #[cfg(test)]
 mod test {
     use crate::error_logger::InspectErr;
-    use log::{error, log, warn};
+    use log::warn;
 
     #[test]
     fn log_err() {
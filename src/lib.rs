@@ -0,0 +1,21 @@
+//! Peer-to-peer networking layer of the Massa test node: the controller keeps a table of known
+//! peers, dials and accepts connections, handshakes with them, and hands the connections over
+//! to the application.
+
+mod error_logger;
+pub mod network;
+
+pub use network::clock::{Clock, MockClock, SharedClock, SystemClock};
+pub use network::codec::{self, CodecError};
+pub use network::config::{NetworkConfig, NetworkConfigBuilder, NetworkConfigError};
+pub use network::controller::{
+    BackgroundTask, ConnectionRejection, NetworkController, NetworkControllerBuilder,
+    NetworkControllerError, NetworkControllerEvent,
+};
+pub use network::event::{EventKind, EventStream, EventStreamError, NetworkEvent};
+pub use network::message::ChannelMessage;
+pub use network::peer::{Peer, PeerError, PeerStatus};
+pub use network::queue::OverflowPolicy;
+pub use network::transport::{
+    BoxedConnection, Connection, Listener, MemoryNetwork, MemoryTransport, TcpTransport, Transport,
+};
@@ -1,71 +1,104 @@
-use crate::network::controller::{NetworkController, NetworkControllerError};
-use crate::network::peer::Peer;
-use log::{error, info};
-use std::collections::HashMap;
-use std::net::IpAddr;
+use crate::cli::{Cli, Command, ConfigCommand};
+use clap::Parser;
+use log::{info, warn};
+use std::io;
+use test_massa::{
+    NetworkConfig, NetworkController, NetworkControllerError, NetworkControllerEvent, NetworkEvent,
+};
 
-pub mod error_logger;
-pub mod network;
+mod cli;
 
 #[tokio::main]
 async fn main() -> Result<(), NetworkControllerError> {
-    env_logger::init();
-
-    let peers_file = "peers.json";
-    let listen_port = 8080;
-    let target_outgoing_connections: HashMap<IpAddr, Peer> = Default::default();
-    let max_incoming_connections = 16;
-    let max_simultaneous_outgoing_connection_attempts = 16;
-    let max_simultaneous_incoming_connection_attempts = 16;
-    let max_idle_peers = 16;
-    let max_banned_peers = 16;
-    let peer_file_dump_interval_seconds = 2;
+    let cli = Cli::parse();
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(log_level) = &cli.log_level {
+        logger.parse_filters(log_level);
+    }
+    logger.init();
+
+    let config = cli.network_config()?;
+    match &cli.command {
+        None | Some(Command::Run { .. }) => run(config).await,
+        Some(Command::Peers(command)) => cli::peers(command, &config).await,
+        Some(Command::Config(ConfigCommand::Check)) => {
+            print!("{}", toml::to_string(&config).map_err(io::Error::other)?);
+            Ok(())
+        }
+    }
+}
 
+async fn run(config: NetworkConfig) -> Result<(), NetworkControllerError> {
     // launch network controller
-    let mut net = NetworkController::new(
-        peers_file,
-        listen_port,
-        target_outgoing_connections,
-        max_incoming_connections,
-        max_simultaneous_outgoing_connection_attempts,
-        max_simultaneous_incoming_connection_attempts,
-        max_idle_peers,
-        max_banned_peers,
-        peer_file_dump_interval_seconds,
-    )
-    .await?;
+    let net = NetworkController::new(config).await?;
 
     info!("Starting event loop");
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     // loop over messages coming from the network controller
-    loop {
+    let result = loop {
         tokio::select! {
+            _ = &mut shutdown => break Ok(()),
             msg = net.wait_event() =>
-                 match msg? {
-                    network::controller::NetworkControllerEvent::CandidateConnection {ip, socket, is_outgoing} => {
-                        info!("New candidate connection: {ip}");
-                        net.add_peer(ip, Some(socket)).await?;
-                        // ip is the peer ip, and socket is a tokio TCPStream
-                        // triggered when a new TCP connection with a peer is established
-                        // is_outgoing is true if our node has connected to the peer node
-                        // is_outgoing is false if the peer node has connected to our node
-
-                        // here, a handshake is performed by reading/writing data to socket
-                        //  if the handshake succesds, call net.feedback_peer_alive(ip).await; to signal NetworkController to set the peer in InAlive or OutAlive state (this should update last_alive)
-                        //  if handshake fails or the connection closes unexpectedly at any time, call net.feedback_peer_failed(ip).await; to signal NetworkController to set the peer status to Idle  (this should update last_failure)
-
-                        // once the handshake is done, we can use this peer socket in main.rs
+                 match msg {
+                    // A background task stopped for good
+                    Err(err) => break Err(err),
+                    Ok(event) => match event {
+                        NetworkControllerEvent::CandidateConnection {addr, socket, is_outgoing} => {
+                            info!("New candidate connection: {addr} (outgoing: {is_outgoing})");
+                            if let Err(err) = net.add_peer(addr.to_string(), Some(socket)).await {
+                                break Err(err);
+                            }
+                            // addr is the address the peer listens on, and socket is a BoxedConnection: a TCP stream with the default transport
+                            // triggered when a new connection with a peer is established and the handshake is done
+                            // is_outgoing is true if our node has connected to the peer node
+                            // is_outgoing is false if the peer node has connected to our node
+
+                            // the handshake (protocol version, network id, listen port and nonce) is performed by NetworkController,
+                            // which already set the peer in InAlive or OutAlive state
+                            //  if the connection closes unexpectedly at any time, call net.feedback_peer_failed(&addr).await; to signal NetworkController to set the peer status to Idle  (this should update last_failure)
+
+                            // we can use this peer socket in main.rs
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::PeerAlive {addr, is_outgoing}) => {
+                            info!("Peer {addr} is alive (outgoing: {is_outgoing})");
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::PeerClosed {addr}) => {
+                            info!("Connection with {addr} closed");
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::PeerFailed {addr}) => {
+                            info!("Connection with {addr} failed");
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::PeerBanned {addr}) => {
+                            info!("Peer {addr} banned");
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::PeersEvicted {addrs}) => {
+                            info!("Peers evicted from the peer list: {addrs:?}");
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::PeersLearned {addrs}) => {
+                            info!("New peers learned: {addrs:?}");
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::ConnectionRejected {addr, reason}) => {
+                            info!("Rejected connection from {addr}: {reason}");
+                        }
+                        NetworkControllerEvent::Event(NetworkEvent::TaskFailed {task, error, restarting}) => {
+                            warn!("The {task} failed (restarting: {restarting}): {error}");
+                        }
                     }
-                _ => { error!("Error while waiting for a message from the network controller") }
             }
         }
-    }
+    };
+
+    info!("Shutting down");
+    let shutdown = net.shutdown().await;
+    result.and(shutdown)
 
     /*
         NetworkController internally maintains a list of known peers and connections with them.
         It does not read/write on sockets, but only listens/connects
 
         NetworkController::new create a NetworkController object and spawn an async loop that:
-            - maintains a list of known peers identified by their IP addresses: Done
+            - maintains a list of known peers identified by their IP addresses and listen ports: Done
             - each peer in the list has the following properties:
                 - status: enum:
                     Idle : we know about the peer but we aren't currently doing anything with it: Done
@@ -82,20 +115,22 @@ async fn main() -> Result<(), NetworkControllerError> {
                     if None, it means that we never failed to connect or handshake, and that we never banned that peer
             - on startup, the peer list is loaded from the JSON file peers_file (this file should be preloaded with a list of bootstrap peers at first launch): Done
             - every peer_file_dump_interval_seconds seconds, the peer list is dumped to the peers_file JSON file if there have been any changes: Done
-            - always tries to keep target_outgoing_connections peers in a OutAlive status by launching outgoing TCP connections towards the most promising peers when necessary
-                - when starting a connection attempt, set the peer status to OutConnecting
-                - when a TCP connection is established, set the peer status to OutHandshaking and emit a network::controller::NetworkControllerEvent::CandidateConnection event
-                - up to max_simultaneous_outgoing_connection_attempts peers can be in an OutConnecting or OutHandshaking status
-            - listens on port listen_port, accepts incoming TCP connections
-                - when a connection is accepted, set the peer status to InHandshaking and emit a network::controller::NetworkControllerEvent::CandidateConnection event
+            - always tries to keep target_outgoing_connections peers in a OutAlive status by launching outgoing TCP connections towards the most promising peers when necessary: Done
+                - when starting a connection attempt, set the peer status to OutConnecting: Done
+                - when a TCP connection is established, set the peer status to OutHandshaking and perform the handshake: Done
+                - once the handshake is done, set the peer status to OutAlive and emit a NetworkControllerEvent::CandidateConnection event: Done
+                - up to max_simultaneous_outgoing_connection_attempts peers can be in an OutConnecting or OutHandshaking status: Done
+            - listens on port listen_port, accepts incoming TCP connections: Done
+                - when a connection is accepted, set the peer status to InHandshaking and perform the handshake: Done
+                - once the handshake is done, set the peer status to InAlive and emit a NetworkControllerEvent::CandidateConnection event: Done
                     if the peer is absent from the peer list, add it to the peer list: Done
-                - no more than max_incoming_connections peers can have InAlive status, extra connection attemps must be rejected
-                - no more than max_simultaneous_incoming_connection_attempts peers can have InHandshaking status, extra connection attemps must be rejected
-            - no more than max_banned_peers can have Banned status. If necessary, some smartly chosen Banned peers may be dropped to respect this condition.
-            - no more than max_idle_peers can have Idle status. If necessary, some smartly chosen Idle peers may be dropped to respect this condition.
-            - only up to a single TCP connection per peer is allowed (whatever the direction)
+                - no more than max_incoming_connections peers can have InAlive status, extra connection attemps must be rejected: Done
+                - no more than max_simultaneous_incoming_connection_attempts peers can have InHandshaking status, extra connection attemps must be rejected: Done
+            - no more than max_banned_peers can have Banned status. If necessary, some smartly chosen Banned peers may be dropped to respect this condition: Done
+            - no more than max_idle_peers can have Idle status. If necessary, some smartly chosen Idle peers may be dropped to respect this condition: Done
+            - only up to a single TCP connection per peer is allowed (whatever the direction): Done
     */
-    
+
     /*
         call net.feedback_peer_alive(ip).await whenever the peer gives a sign of life (this should update last_alive)
 
@@ -109,3 +144,26 @@ async fn main() -> Result<(), NetworkControllerError> {
             Note that net.get_good_peer_ips() excludes banned peers and sorts the peers from "best" to "worst"
     */
 }
+
+/// Resolves on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(err) => {
+                warn!("Can't listen for SIGTERM: {}", err);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,1503 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::{self, JoinHandle, JoinSet};
+
+use crate::error_logger::InspectErr;
+use crate::network::clock::SharedClock;
+use crate::network::codec;
+use crate::network::config::NetworkConfig;
+use crate::network::controller::{
+    BackgroundTask, ConnectionRejection, NetworkControllerError, PeersLimits,
+};
+use crate::network::event::{EventKind, EventSender, EventStream, NetworkEvent, QueueItem};
+use crate::network::file::PeersFileController;
+use crate::network::handshake::{HandshakeError, HandshakeInfo, Handshaker};
+use crate::network::message::ChannelMessage;
+use crate::network::peer::{self, Peer, PeerError, PeerStatus};
+use crate::network::queue::QueueReceiver;
+use crate::network::random::random_u64;
+use crate::network::transport::{BoxedConnection, Connection, Transport};
+
+/// Delay between two checks of the number of outgoing connections
+const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum duration of an outgoing TCP connection attempt
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the shutdown waits for the `Close` messages to be sent
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Delay before the first restart of a failed background task, doubled at each restart
+const TASK_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound of the restart delay, a task up for longer than that starts its backoff over
+const MAX_TASK_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// Pause of the listener when the process is out of file descriptors or memory
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Commands and task messages waiting for the actor, beyond which their senders wait
+pub const COMMAND_CAPACITY: usize = 1024;
+
+type Reply<T> = oneshot::Sender<Result<T, NetworkControllerError>>;
+
+/// Requests of the `NetworkController` handles
+pub enum Command {
+    AddPeer {
+        addr: String,
+        socket: Option<BoxedConnection>,
+        reply: Reply<()>,
+    },
+    RemovePeer {
+        addr: SocketAddr,
+        reply: Reply<()>,
+    },
+    Feedback {
+        addr: SocketAddr,
+        feedback: Feedback,
+        reply: Reply<()>,
+    },
+    PeerList {
+        addrs: Vec<SocketAddr>,
+        reply: Reply<()>,
+    },
+    GoodPeers {
+        reply: oneshot::Sender<Vec<SocketAddr>>,
+    },
+    Subscribe {
+        kinds: Option<Vec<EventKind>>,
+        reply: oneshot::Sender<EventStream>,
+    },
+    Shutdown {
+        reply: Reply<()>,
+    },
+}
+
+/// What the application tells about a peer
+#[derive(Debug, Clone, Copy)]
+pub enum Feedback {
+    Alive,
+    Banned,
+    Failed,
+    Closed,
+}
+
+/// Requests and reports of the background tasks
+enum TaskMessage {
+    SelectPeersToDial {
+        reply: oneshot::Sender<Vec<SocketAddr>>,
+    },
+    /// Whether to handshake with the dialed peer is replied
+    Dialed {
+        addr: SocketAddr,
+        outcome: DialOutcome,
+        reply: oneshot::Sender<bool>,
+    },
+    Accept {
+        addr: SocketAddr,
+        reply: oneshot::Sender<Result<(), ConnectionRejection>>,
+    },
+    Handshaked {
+        addr: SocketAddr,
+        is_outgoing: bool,
+        socket: BoxedConnection,
+        handshake: Result<HandshakeInfo, HandshakeError>,
+    },
+}
+
+enum DialOutcome {
+    Connected,
+    /// We dialed one of our own addresses on our own port
+    OwnAddress,
+    Failed,
+}
+
+/// Send a request to the actor and wait for its reply.
+pub async fn request<M, T>(
+    sender: &mpsc::Sender<M>,
+    message: impl FnOnce(oneshot::Sender<T>) -> M,
+) -> Result<T, NetworkControllerError> {
+    let (reply, response) = oneshot::channel();
+    sender
+        .send(message(reply))
+        .await
+        .map_err(|_| NetworkControllerError::ClosedChanel)?;
+    response
+        .await
+        .map_err(|_| NetworkControllerError::ClosedChanel)
+}
+
+/// Single owner of the peer table: the handles and the background tasks go through it with
+/// messages, so no lock is ever held across network I/O.
+pub struct NetworkActor {
+    peers: HashMap<SocketAddr, Peer>,
+    file_controller: PeersFileController,
+    limits: PeersLimits,
+    listen_ip: IpAddr,
+    listen_port: u16,
+    target_outgoing_connections: usize,
+    max_incoming_connections: usize,
+    max_simultaneous_outgoing_connection_attempts: usize,
+    max_simultaneous_incoming_connection_attempts: usize,
+    events: EventSender,
+    clock: SharedClock,
+}
+
+/// What the actor needs to stop the background tasks
+struct Tasks {
+    listener: JoinHandle<Result<(), NetworkControllerError>>,
+    connector: JoinHandle<Result<(), NetworkControllerError>>,
+    stop_sender: watch::Sender<bool>,
+    /// Never receives anything, but is closed once the dial and handshake tasks, which hold its
+    /// senders, are all done
+    in_flight_receiver: mpsc::Receiver<()>,
+    /// How long the shutdown waits for the in-flight connection attempts and handshakes: long
+    /// enough for a dial followed by a handshake to time out
+    in_flight_timeout: Duration,
+    /// To close the connections `wait_event` didn't get
+    event_receiver: Arc<QueueReceiver<QueueItem>>,
+}
+
+impl NetworkActor {
+    /// Load the peers, then start the background tasks and the actor serving `commands`.
+    pub fn spawn(
+        config: NetworkConfig,
+        transport: Arc<dyn Transport>,
+        clock: SharedClock,
+        events: EventSender,
+        event_receiver: Arc<QueueReceiver<QueueItem>>,
+        commands: mpsc::Receiver<Command>,
+    ) -> Result<(), NetworkControllerError> {
+        let NetworkConfig {
+            peers_file,
+            listen_ip,
+            listen_port,
+            target_outgoing_connections,
+            max_incoming_connections,
+            max_simultaneous_outgoing_connection_attempts,
+            max_simultaneous_incoming_connection_attempts,
+            max_idle_peers,
+            max_banned_peers,
+            peer_file_dump_interval_seconds,
+            network_id,
+            handshake_timeout_seconds,
+            task_restart_attempts,
+            ..
+        } = config;
+
+        let file_controller =
+            PeersFileController::new(&peers_file, listen_port).with_clock(clock.clone());
+        let limits = PeersLimits {
+            max_idle_peers,
+            max_banned_peers,
+        };
+        let mut peers = file_controller.read_file()?;
+        Self::evict_peers(&mut peers, limits);
+        let actor = NetworkActor {
+            peers,
+            file_controller,
+            limits,
+            listen_ip,
+            listen_port,
+            target_outgoing_connections,
+            max_incoming_connections,
+            max_simultaneous_outgoing_connection_attempts,
+            max_simultaneous_incoming_connection_attempts,
+            events: events.clone(),
+            clock,
+        };
+
+        let handshake_timeout = Duration::from_secs(handshake_timeout_seconds);
+        let handshaker = Arc::new(Handshaker::new(&network_id, listen_port, handshake_timeout));
+        let (task_sender, task_messages) = mpsc::channel(COMMAND_CAPACITY);
+        let (stop_sender, stop_receiver) = watch::channel(false);
+        let (in_flight_sender, in_flight_receiver) = mpsc::channel::<()>(1);
+
+        let connector = {
+            let task_sender = task_sender.clone();
+            let transport = transport.clone();
+            let handshaker = handshaker.clone();
+            let events = events.clone();
+            let stop_receiver = stop_receiver.clone();
+            let in_flight = in_flight_sender.clone();
+            task::spawn(Self::supervise(
+                BackgroundTask::Connector,
+                task_restart_attempts,
+                events.clone(),
+                stop_receiver.clone(),
+                move || {
+                    Self::connect_to_peers(
+                        task_sender.clone(),
+                        transport.clone(),
+                        handshaker.clone(),
+                        events.clone(),
+                        listen_port,
+                        stop_receiver.clone(),
+                        in_flight.clone(),
+                    )
+                },
+            ))
+        };
+        let listener = task::spawn(Self::supervise(
+            BackgroundTask::Listener,
+            task_restart_attempts,
+            events.clone(),
+            stop_receiver.clone(),
+            move || {
+                Self::listen_new_peers(
+                    SocketAddr::new(listen_ip, listen_port),
+                    task_sender.clone(),
+                    transport.clone(),
+                    handshaker.clone(),
+                    events.clone(),
+                    stop_receiver.clone(),
+                    in_flight_sender.clone(),
+                )
+            },
+        ));
+
+        let tasks = Tasks {
+            listener,
+            connector,
+            stop_sender,
+            in_flight_receiver,
+            in_flight_timeout: CONNECT_TIMEOUT + handshake_timeout,
+            event_receiver,
+        };
+        task::spawn(actor.run(
+            commands,
+            task_messages,
+            tasks,
+            Duration::from_secs(peer_file_dump_interval_seconds),
+        ));
+
+        Ok(())
+    }
+
+    /// Serve the commands and the task messages, and dump the peers file periodically, until a
+    /// handle asks for a shutdown or all of them are gone.
+    async fn run(
+        mut self,
+        mut commands: mpsc::Receiver<Command>,
+        mut task_messages: mpsc::Receiver<TaskMessage>,
+        tasks: Tasks,
+        dump_interval: Duration,
+    ) {
+        let mut dump_interval = tokio::time::interval(dump_interval);
+        let reply = loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Shutdown { reply }) => break Some(reply),
+                    Some(command) => self.handle_command(command),
+                    None => break None,
+                },
+                Some(message) = task_messages.recv() => self.handle_task_message(message),
+                _ = dump_interval.tick() => {
+                    if let Err(err) = self.file_controller.write_file(&self.peers).await {
+                        error!("Can't dump the peers file: {}", err);
+                    }
+                }
+            }
+            self.close_dropped_connections();
+        };
+
+        let result = self.shutdown(task_messages, tasks).await;
+        match reply {
+            Some(reply) => {
+                let _ = reply.send(result);
+            }
+            None => {
+                if let Err(err) = result {
+                    error!("{}", err);
+                }
+            }
+        }
+    }
+
+    /// See `NetworkController::shutdown`.
+    async fn shutdown(
+        &mut self,
+        mut task_messages: mpsc::Receiver<TaskMessage>,
+        mut tasks: Tasks,
+    ) -> Result<(), NetworkControllerError> {
+        let _ = tasks.stop_sender.send(true);
+        let mut errors = Vec::new();
+        for handle in [tasks.listener, tasks.connector] {
+            match self.serve_until(&mut task_messages, handle).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => errors.push(err),
+                Err(err) => errors.push(io::Error::other(err).into()),
+            }
+        }
+
+        let in_flight =
+            tokio::time::timeout(tasks.in_flight_timeout, tasks.in_flight_receiver.recv());
+        if self
+            .serve_until(&mut task_messages, in_flight)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Handshakes still in progress after {:?}",
+                tasks.in_flight_timeout
+            );
+        }
+        while let Ok(message) = task_messages.try_recv() {
+            self.handle_task_message(message);
+        }
+        self.close_connections(&tasks.event_receiver).await;
+
+        if let Err(err) = self.file_controller.write_file(&self.peers).await {
+            errors.push(err.into());
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(NetworkControllerError::TasksFailed(errors)),
+        }
+    }
+
+    /// Keep serving the background tasks while waiting for `until`.
+    async fn serve_until<F: Future>(
+        &mut self,
+        task_messages: &mut mpsc::Receiver<TaskMessage>,
+        until: F,
+    ) -> F::Output {
+        tokio::pin!(until);
+        loop {
+            tokio::select! {
+                output = &mut until => return output,
+                Some(message) = task_messages.recv() => self.handle_task_message(message),
+            }
+        }
+    }
+
+    /// Send `Close` to the connected peers, including the ones whose connection is still waiting
+    /// for `wait_event`, and set them back to Idle. The incoming connections still handshaking
+    /// are tracked under the port they come from, which nobody listens on, so they are dropped.
+    async fn close_connections(&mut self, receiver: &QueueReceiver<QueueItem>) {
+        let mut sockets = Vec::new();
+        while let Some(item) = receiver.try_recv() {
+            if let QueueItem::Connection { addr, socket, .. } = item {
+                sockets.push((addr, socket));
+            }
+        }
+        self.peers
+            .retain(|_, peer| peer.status() != PeerStatus::InHandshaking);
+        let now = self.clock.now();
+        for (addr, peer) in self.peers.iter_mut() {
+            if let Some(socket) = peer.socket.take() {
+                sockets.push((*addr, socket));
+            }
+            if peer.status().is_connected() {
+                let _ = peer.closed(now);
+            }
+        }
+        self.file_controller.changed();
+
+        let mut closing = JoinSet::new();
+        for (addr, mut socket) in sockets {
+            closing.spawn(async move {
+                if let Err(err) = codec::write_message(&mut socket, &ChannelMessage::Close).await {
+                    info!("Can't close the connection with {}: {}", addr, err);
+                }
+            });
+        }
+        let closed = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while closing.join_next().await.is_some() {}
+        });
+        if closed.await.is_err() {
+            warn!("Some connections were not closed after {:?}", CLOSE_TIMEOUT);
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::AddPeer {
+                addr,
+                socket,
+                reply,
+            } => {
+                let _ = reply.send(self.add_peer(&addr, socket));
+            }
+            Command::RemovePeer { addr, reply } => {
+                let _ = reply.send(self.remove_peer(&addr));
+            }
+            Command::Feedback {
+                addr,
+                feedback,
+                reply,
+            } => {
+                let _ = reply.send(self.feedback(&addr, feedback));
+            }
+            Command::PeerList { addrs, reply } => {
+                self.merge_peer_list(addrs);
+                let _ = reply.send(Ok(()));
+            }
+            Command::GoodPeers { reply } => {
+                let _ = reply.send(Self::rank_peers(&self.peers, self.clock.now()));
+            }
+            Command::Subscribe { kinds, reply } => {
+                let _ = reply.send(self.events.subscribe(kinds));
+            }
+            // Handled by `run`
+            Command::Shutdown { .. } => {}
+        }
+    }
+
+    fn handle_task_message(&mut self, message: TaskMessage) {
+        match message {
+            TaskMessage::SelectPeersToDial { reply } => {
+                let candidates = Self::select_peers_to_dial(
+                    &mut self.peers,
+                    self.target_outgoing_connections,
+                    self.max_simultaneous_outgoing_connection_attempts,
+                    self.clock.now(),
+                );
+                if !candidates.is_empty() {
+                    self.file_controller.changed();
+                }
+                let _ = reply.send(candidates);
+            }
+            TaskMessage::Dialed {
+                addr,
+                outcome,
+                reply,
+            } => {
+                let _ = reply.send(self.dialed(addr, outcome));
+            }
+            TaskMessage::Accept { addr, reply } => {
+                let accepted = Self::accept_incoming(
+                    &mut self.peers,
+                    addr,
+                    self.max_incoming_connections,
+                    self.max_simultaneous_incoming_connection_attempts,
+                    self.clock.now(),
+                );
+                // Only the attempts of banned peers change what is persisted
+                if accepted == Err(ConnectionRejection::Banned) {
+                    self.file_controller.changed();
+                }
+                if let Err(reason) = accepted {
+                    debug!("Rejected connection from {}: {}", addr, reason);
+                    self.events
+                        .notify(NetworkEvent::ConnectionRejected { addr, reason });
+                }
+                let _ = reply.send(accepted);
+            }
+            TaskMessage::Handshaked {
+                addr,
+                is_outgoing,
+                socket,
+                handshake,
+            } => self.handshaked(addr, is_outgoing, socket, handshake),
+        }
+    }
+
+    /// See `NetworkController::add_peer`.
+    fn add_peer(
+        &mut self,
+        addr: &str,
+        socket: Option<BoxedConnection>,
+    ) -> Result<(), NetworkControllerError> {
+        let addr = peer::parse_addr(addr, self.listen_port).map_err(PeerError::from)?;
+        // Keep the state of an already known peer, only attach the socket of its connection
+        let known = self.peers.get_mut(&addr);
+        if let Some(socket) = socket {
+            return match known {
+                Some(known) if known.status().is_connected() => {
+                    known.socket = Some(socket);
+                    Ok(())
+                }
+                _ => Err(NetworkControllerError::NotConnected(addr)),
+            };
+        }
+        if known.is_some() {
+            return Ok(());
+        }
+        self.peers.insert(addr, Peer::from_addr(addr));
+        self.file_controller.changed();
+        self.evict_and_report();
+
+        Ok(())
+    }
+
+    /// Forget the peer, dropping its socket if we hold it.
+    fn remove_peer(&mut self, addr: &SocketAddr) -> Result<(), NetworkControllerError> {
+        self.peers
+            .remove(addr)
+            .ok_or(NetworkControllerError::UnknownPeer(*addr))?;
+        self.file_controller.changed();
+
+        Ok(())
+    }
+
+    /// Apply the feedback to the peer, and emit an event if its status changed.
+    fn feedback(
+        &mut self,
+        addr: &SocketAddr,
+        feedback: Feedback,
+    ) -> Result<(), NetworkControllerError> {
+        let peer = self
+            .peers
+            .get_mut(addr)
+            .ok_or(NetworkControllerError::UnknownPeer(*addr))?;
+        let previous_status = peer.status();
+        let now = self.clock.now();
+        match feedback {
+            Feedback::Alive => peer.alive(now),
+            Feedback::Banned => {
+                peer.banned(now);
+                Ok(())
+            }
+            Feedback::Failed => peer.failed(now, random_u64()),
+            Feedback::Closed => peer.closed(now),
+        }
+        .inspect_error(|err| warn!("{}", err))?;
+
+        if peer.status() != previous_status {
+            let addr = *addr;
+            self.events.notify(match feedback {
+                Feedback::Alive => NetworkEvent::PeerAlive {
+                    addr,
+                    is_outgoing: peer.status() == PeerStatus::OutAlive,
+                },
+                Feedback::Banned => NetworkEvent::PeerBanned { addr },
+                Feedback::Failed => NetworkEvent::PeerFailed { addr },
+                Feedback::Closed => NetworkEvent::PeerClosed { addr },
+            });
+        }
+        self.file_controller.changed();
+        self.evict_and_report();
+
+        Ok(())
+    }
+
+    /// See `NetworkController::feedback_peer_list`.
+    fn merge_peer_list(&mut self, addrs: Vec<SocketAddr>) {
+        let idle = Self::count_status(&self.peers, &[PeerStatus::Idle]);
+        let mut free_slots = self.limits.max_idle_peers.saturating_sub(idle);
+
+        let banned_ips = Self::banned_ips(&self.peers);
+        let own_addr = SocketAddr::new(self.listen_ip, self.listen_port);
+        let mut learned = Vec::new();
+        for addr in addrs {
+            if free_slots == 0 {
+                break;
+            }
+            if !Self::is_routable(&addr, self.listen_ip)
+                || addr == own_addr
+                || self.peers.contains_key(&addr)
+                || banned_ips.contains(&addr.ip())
+            {
+                continue;
+            }
+            self.peers.insert(addr, Peer::from_addr(addr));
+            learned.push(addr);
+            free_slots -= 1;
+        }
+        if !learned.is_empty() {
+            self.file_controller.changed();
+            self.events
+                .notify(NetworkEvent::PeersLearned { addrs: learned });
+        }
+    }
+
+    /// Move the dialed peer to OutHandshaking, and tell whether to handshake with it.
+    fn dialed(&mut self, addr: SocketAddr, outcome: DialOutcome) -> bool {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return false;
+        };
+        // The peer may have been banned, or connected to us, while we were dialing
+        if peer.status() != PeerStatus::OutConnecting {
+            return false;
+        }
+        self.file_controller.changed();
+        match outcome {
+            DialOutcome::OwnAddress => {
+                peer.mark_self();
+                false
+            }
+            DialOutcome::Connected => {
+                peer.handshake(true);
+                true
+            }
+            DialOutcome::Failed => {
+                let _ = peer.failed(self.clock.now(), random_u64());
+                self.events.notify(NetworkEvent::PeerFailed { addr });
+                self.evict_and_report();
+                false
+            }
+        }
+    }
+
+    /// Set the handshaked peer alive and hand its connection over to `wait_event`, or set it
+    /// back to Idle if the handshake failed. `addr` is the dialed address of an outgoing
+    /// connection, or the address an incoming connection comes from.
+    fn handshaked(
+        &mut self,
+        addr: SocketAddr,
+        is_outgoing: bool,
+        socket: BoxedConnection,
+        handshake: Result<HandshakeInfo, HandshakeError>,
+    ) {
+        // The failed incoming handshakes only drop their InHandshaking entry, which isn't persisted
+        if handshake.is_ok() || is_outgoing {
+            self.file_controller.changed();
+        }
+        let remote = match handshake {
+            Ok(remote) => remote,
+            // Only the dialed address is known to be ours, the incoming side may come through NAT
+            Err(HandshakeError::SelfConnection) if is_outgoing => {
+                info!("{} is our own node", addr);
+                if let Some(peer) = self.peers.get_mut(&addr) {
+                    peer.mark_self();
+                }
+                return;
+            }
+            Err(err) => {
+                info!("Handshake with {} failed: {}", addr, err);
+                if !is_outgoing {
+                    self.peers.remove(&addr);
+                } else if let Some(peer) = self.peers.get_mut(&addr) {
+                    if peer.failed(self.clock.now(), random_u64()).is_ok()
+                        && peer.status() == PeerStatus::Idle
+                    {
+                        self.events.notify(NetworkEvent::PeerFailed { addr });
+                    }
+                    self.evict_and_report();
+                }
+                return;
+            }
+        };
+
+        let addr = if is_outgoing {
+            addr
+        } else {
+            let peer_addr = SocketAddr::new(addr.ip(), remote.listen_port);
+            let our_addr = match socket.local_addr() {
+                Ok(local) => SocketAddr::new(local.ip(), self.listen_port),
+                Err(err) => {
+                    warn!("Connection from {} is unusable: {}", addr, err);
+                    self.peers.remove(&addr);
+                    return;
+                }
+            };
+            let registered = Self::register_incoming(
+                &mut self.peers,
+                addr,
+                our_addr,
+                peer_addr,
+                self.clock.now(),
+            );
+            self.evict_and_report();
+            if let Err(reason) = registered {
+                debug!("Rejected connection from {}: {}", peer_addr, reason);
+                self.events.notify(NetworkEvent::ConnectionRejected {
+                    addr: peer_addr,
+                    reason,
+                });
+                return;
+            }
+            peer_addr
+        };
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        if is_outgoing && peer.status() != PeerStatus::OutHandshaking {
+            // An incoming connection from the peer won a simultaneous open, unless the peer was
+            // banned or failed in the meantime
+            if !peer.consume_superseded_outgoing() {
+                warn!("{} is no longer handshaking", addr);
+            }
+            return;
+        }
+        let now = self.clock.now();
+        if let Err(err) = peer.alive(now) {
+            warn!("{}", err);
+            return;
+        }
+        info!("Handshake done with {}", addr);
+        match self.events.connection(addr, socket, is_outgoing) {
+            Ok(()) => {}
+            // Not the fault of the peer
+            Err(err @ NetworkControllerError::QueueFull { .. }) => {
+                info!("{}", err);
+                let _ = peer.closed(now);
+                return;
+            }
+            Err(err) => {
+                warn!("{}", err);
+                let _ = peer.failed(now, random_u64());
+                return;
+            }
+        }
+        self.events
+            .notify(NetworkEvent::PeerAlive { addr, is_outgoing });
+    }
+
+    /// The connections dropped from the full queue never reach the application, their peers go
+    /// back to Idle.
+    fn close_dropped_connections(&mut self) {
+        let now = self.clock.now();
+        for addr in self.events.take_dropped_connections() {
+            let Some(peer) = self.peers.get_mut(&addr) else {
+                continue;
+            };
+            if peer.status().is_alive() && peer.closed(now).is_ok() {
+                self.events.notify(NetworkEvent::PeerClosed { addr });
+                self.file_controller.changed();
+            }
+        }
+    }
+
+    fn evict_and_report(&mut self) {
+        let evicted = Self::evict_peers(&mut self.peers, self.limits);
+        if evicted.is_empty() {
+            return;
+        }
+        info!("Evicted peers: {:?}", evicted);
+        self.events
+            .notify(NetworkEvent::PeersEvicted { addrs: evicted });
+    }
+
+    /// Run a background task, restarting it with an exponential backoff when it fails, and
+    /// report its failures in the channel. The last error is returned once out of restarts.
+    async fn supervise<F, T>(
+        task: BackgroundTask,
+        restart_attempts: u32,
+        events: EventSender,
+        mut stop_receiver: watch::Receiver<bool>,
+        mut run: F,
+    ) -> Result<(), NetworkControllerError>
+    where
+        F: FnMut() -> T,
+        T: Future<Output = Result<(), NetworkControllerError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let started = tokio::time::Instant::now();
+            let Err(err) = run().await else {
+                return Ok(());
+            };
+            error!("The {} failed: {}", task, err);
+            if started.elapsed() > MAX_TASK_RESTART_DELAY {
+                attempt = 0;
+            }
+            let restarting = attempt < restart_attempts;
+            events.notify_always(NetworkEvent::TaskFailed {
+                task,
+                error: err.to_string(),
+                restarting,
+            });
+            if !restarting {
+                return Err(err);
+            }
+
+            let delay = TASK_RESTART_DELAY
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(MAX_TASK_RESTART_DELAY);
+            attempt += 1;
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => info!("Restarting the {}", task),
+                _ = stop_receiver.changed() => return Err(err),
+            }
+        }
+    }
+
+    /// Accept incoming connections and handshake with them, unless the actor rejects them.
+    async fn listen_new_peers(
+        listen_addr: SocketAddr,
+        actor: mpsc::Sender<TaskMessage>,
+        transport: Arc<dyn Transport>,
+        handshaker: Arc<Handshaker>,
+        events: EventSender,
+        mut stop_receiver: watch::Receiver<bool>,
+        in_flight: mpsc::Sender<()>,
+    ) -> Result<(), NetworkControllerError> {
+        let mut listener = transport.listen(listen_addr).await?;
+
+        loop {
+            // With the Block policy, connections wait in the listen backlog while the queue is full
+            tokio::select! {
+                _ = events.ready() => {}
+                _ = stop_receiver.changed() => return Ok(()),
+            }
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stop_receiver.changed() => return Ok(()),
+            };
+            let (socket, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) if Self::is_connection_error(&err) => {
+                    debug!("Incoming connection failed: {}", err);
+                    continue;
+                }
+                Err(err) if Self::is_resource_exhaustion(&err) => {
+                    warn!("Can't accept incoming connections: {}", err);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            // Dropping the socket of a rejected connection closes it
+            if request(&actor, |reply| TaskMessage::Accept { addr, reply })
+                .await?
+                .is_err()
+            {
+                continue;
+            }
+            task::spawn(Self::handshake_peer(
+                socket,
+                addr,
+                false,
+                actor.clone(),
+                handshaker.clone(),
+                events.clone(),
+                in_flight.clone(),
+            ));
+        }
+    }
+
+    /// Keep `target_outgoing_connections` peers in OutAlive status by periodically dialing the
+    /// most promising Idle peers the actor selects.
+    async fn connect_to_peers(
+        actor: mpsc::Sender<TaskMessage>,
+        transport: Arc<dyn Transport>,
+        handshaker: Arc<Handshaker>,
+        events: EventSender,
+        listen_port: u16,
+        mut stop_receiver: watch::Receiver<bool>,
+        in_flight: mpsc::Sender<()>,
+    ) -> Result<(), NetworkControllerError> {
+        let mut interval = tokio::time::interval(CONNECT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop_receiver.changed() => return Ok(()),
+            }
+
+            let candidates =
+                request(&actor, |reply| TaskMessage::SelectPeersToDial { reply }).await?;
+            for addr in candidates {
+                task::spawn(Self::dial_peer(
+                    addr,
+                    listen_port,
+                    actor.clone(),
+                    transport.clone(),
+                    handshaker.clone(),
+                    events.clone(),
+                    in_flight.clone(),
+                ));
+            }
+        }
+    }
+
+    async fn dial_peer(
+        addr: SocketAddr,
+        listen_port: u16,
+        actor: mpsc::Sender<TaskMessage>,
+        transport: Arc<dyn Transport>,
+        handshaker: Arc<Handshaker>,
+        events: EventSender,
+        in_flight: mpsc::Sender<()>,
+    ) {
+        let connection = tokio::time::timeout(CONNECT_TIMEOUT, transport.dial(addr)).await;
+        let (outcome, socket) = match connection {
+            Ok(Ok(socket)) if Self::is_local_address(&*socket, addr, listen_port) => {
+                info!("{} is our own address", addr);
+                (DialOutcome::OwnAddress, None)
+            }
+            Ok(Ok(socket)) => (DialOutcome::Connected, Some(socket)),
+            Ok(Err(err)) => {
+                info!("Can't connect to {}: {}", addr, err);
+                (DialOutcome::Failed, None)
+            }
+            Err(_) => {
+                info!("Connection to {} timed out", addr);
+                (DialOutcome::Failed, None)
+            }
+        };
+
+        let handshake = request(&actor, |reply| TaskMessage::Dialed {
+            addr,
+            outcome,
+            reply,
+        })
+        .await;
+        if let (Ok(true), Some(socket)) = (handshake, socket) {
+            Self::handshake_peer(socket, addr, true, actor, handshaker, events, in_flight).await;
+        }
+    }
+
+    /// Handshake with a newly connected peer, and report the outcome to the actor.
+    async fn handshake_peer(
+        mut socket: BoxedConnection,
+        addr: SocketAddr,
+        is_outgoing: bool,
+        actor: mpsc::Sender<TaskMessage>,
+        handshaker: Arc<Handshaker>,
+        events: EventSender,
+        _in_flight: mpsc::Sender<()>,
+    ) {
+        let handshake = handshaker.run(&mut socket).await;
+        if handshake.is_ok() {
+            events.ready().await;
+        }
+
+        let message = TaskMessage::Handshaked {
+            addr,
+            is_outgoing,
+            socket,
+            handshake,
+        };
+        if actor.send(message).await.is_err() {
+            debug!("Handshake with {} done after the shutdown", addr);
+        }
+    }
+
+    /// We dialed one of our own addresses on our own port.
+    fn is_local_address(socket: &dyn Connection, addr: SocketAddr, listen_port: u16) -> bool {
+        addr.port() == listen_port
+            && socket
+                .local_addr()
+                .is_ok_and(|local| local.ip() == addr.ip())
+    }
+
+    /// A ban applies to the whole IP of the peer, whatever the port.
+    fn banned_ips(peers: &HashMap<SocketAddr, Peer>) -> HashSet<IpAddr> {
+        peers
+            .values()
+            .filter(|peer| peer.status() == PeerStatus::Banned)
+            .map(|peer| peer.addr().ip())
+            .collect()
+    }
+
+    /// The port an incoming connection comes from isn't the one the peer listens on, which is
+    /// only learned in the handshake. Until then, the connection is tracked as an InHandshaking
+    /// peer under the address it comes from.
+    fn accept_incoming(
+        peers: &mut HashMap<SocketAddr, Peer>,
+        addr: SocketAddr,
+        max_incoming_connections: usize,
+        max_simultaneous_incoming_connection_attempts: usize,
+        now: DateTime<Utc>,
+    ) -> Result<(), ConnectionRejection> {
+        let in_alive = Self::count_status(peers, &[PeerStatus::InAlive]);
+        let in_handshaking = Self::count_status(peers, &[PeerStatus::InHandshaking]);
+
+        // A ban applies to the whole IP, whatever the port its connections come from
+        let mut banned = false;
+        for peer in peers
+            .values_mut()
+            .filter(|peer| peer.addr().ip() == addr.ip() && peer.status() == PeerStatus::Banned)
+        {
+            // Keep track of the last attempt of a banned peer
+            peer.banned(now);
+            banned = true;
+        }
+        if banned {
+            return Err(ConnectionRejection::Banned);
+        }
+        if in_alive + in_handshaking >= max_incoming_connections {
+            return Err(ConnectionRejection::TooManyIncomingConnections);
+        }
+        if in_handshaking >= max_simultaneous_incoming_connection_attempts {
+            return Err(ConnectionRejection::TooManyIncomingAttempts);
+        }
+        if peers.contains_key(&addr) {
+            return Err(ConnectionRejection::AlreadyConnected);
+        }
+        let mut peer = Peer::from_addr(addr);
+        peer.handshake(false);
+        peers.insert(addr, peer);
+
+        Ok(())
+    }
+
+    /// Move a handshaked incoming connection to the address its peer listens on. Only one
+    /// connection per peer is allowed: when both nodes dial each other at the same time, both
+    /// keep the connection dialed by the node with the lowest address.
+    fn register_incoming(
+        peers: &mut HashMap<SocketAddr, Peer>,
+        connection_addr: SocketAddr,
+        our_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        now: DateTime<Utc>,
+    ) -> Result<(), ConnectionRejection> {
+        peers.remove(&connection_addr);
+        let peer = peers
+            .entry(peer_addr)
+            .or_insert_with(|| Peer::from_addr(peer_addr));
+        if peer.is_self() {
+            return Err(ConnectionRejection::SelfConnection);
+        }
+        match peer.status() {
+            PeerStatus::Idle => peer.handshake(false),
+            PeerStatus::Banned => {
+                peer.banned(now);
+                return Err(ConnectionRejection::Banned);
+            }
+            // The outgoing connection is dropped by `dial_peer` if still connecting, or closed
+            // by the remote peer which rejects it on its side
+            PeerStatus::OutConnecting | PeerStatus::OutHandshaking | PeerStatus::OutAlive
+                if peer_addr < our_addr =>
+            {
+                peer.supersede_outgoing()
+            }
+            _ => return Err(ConnectionRejection::AlreadyConnected),
+        }
+
+        Ok(())
+    }
+
+    /// Set the best Idle peers in OutConnecting status, without exceeding the target of OutAlive
+    /// peers nor the maximum of simultaneous outgoing attempts, and return their addresses.
+    fn select_peers_to_dial(
+        peers: &mut HashMap<SocketAddr, Peer>,
+        target_outgoing_connections: usize,
+        max_simultaneous_outgoing_connection_attempts: usize,
+        now: DateTime<Utc>,
+    ) -> Vec<SocketAddr> {
+        let out_alive = Self::count_status(peers, &[PeerStatus::OutAlive]);
+        let attempts = Self::count_status(
+            peers,
+            &[PeerStatus::OutConnecting, PeerStatus::OutHandshaking],
+        );
+        let wanted = target_outgoing_connections
+            .saturating_sub(out_alive + attempts)
+            .min(max_simultaneous_outgoing_connection_attempts.saturating_sub(attempts));
+        if wanted == 0 {
+            return Vec::new();
+        }
+
+        let banned_ips = Self::banned_ips(peers);
+        let mut idle: Vec<&mut Peer> = peers
+            .values_mut()
+            .filter(|peer| {
+                peer.status() == PeerStatus::Idle
+                    && !peer.is_self()
+                    && peer.can_dial(now)
+                    && !banned_ips.contains(&peer.addr().ip())
+            })
+            .collect();
+        idle.sort_by_key(|peer| Reverse(peer.score(now)));
+
+        idle.into_iter()
+            .take(wanted)
+            .map(|peer| {
+                peer.connecting();
+                *peer.addr()
+            })
+            .collect()
+    }
+
+    /// Drop Idle and Banned peers beyond their limits, and return their addresses.
+    ///
+    /// Idle peers that never reached an alive status go first, starting with the oldest
+    /// failure, then the peers that were never tried, then the peers alive the longest time ago.
+    /// Banned peers go by oldest ban or connection attempt.
+    fn evict_peers(peers: &mut HashMap<SocketAddr, Peer>, limits: PeersLimits) -> Vec<SocketAddr> {
+        let mut idle: Vec<&Peer> = peers
+            .values()
+            .filter(|peer| peer.status() == PeerStatus::Idle && !peer.is_self())
+            .collect();
+        idle.sort_by_key(|peer| {
+            (
+                peer.last_alive(),
+                peer.last_failure().is_none(),
+                peer.last_failure(),
+            )
+        });
+        let mut banned: Vec<&Peer> = peers
+            .values()
+            .filter(|peer| peer.status() == PeerStatus::Banned)
+            .collect();
+        banned.sort_by_key(|peer| peer.last_failure());
+
+        let evicted: Vec<SocketAddr> = idle
+            .iter()
+            .take(idle.len().saturating_sub(limits.max_idle_peers))
+            .chain(
+                banned
+                    .iter()
+                    .take(banned.len().saturating_sub(limits.max_banned_peers)),
+            )
+            .map(|peer| *peer.addr())
+            .collect();
+        for addr in &evicted {
+            peers.remove(addr);
+        }
+
+        evicted
+    }
+
+    fn count_status(peers: &HashMap<SocketAddr, Peer>, statuses: &[PeerStatus]) -> usize {
+        peers
+            .values()
+            .filter(|peer| statuses.contains(&peer.status()))
+            .count()
+    }
+
+    /// Backs `NetworkController::get_good_peer_ips`.
+    fn rank_peers(peers: &HashMap<SocketAddr, Peer>, now: DateTime<Utc>) -> Vec<SocketAddr> {
+        let mut good: Vec<&Peer> = peers
+            .values()
+            // InHandshaking peers are only known by the address their connection comes from
+            .filter(|peer| {
+                !matches!(
+                    peer.status(),
+                    PeerStatus::Banned | PeerStatus::InHandshaking
+                ) && !peer.is_self()
+            })
+            .collect();
+        good.sort_by_key(|peer| (Reverse(peer.score(now)), *peer.addr()));
+
+        good.into_iter().map(|peer| *peer.addr()).collect()
+    }
+
+    /// Addresses sent by other peers that can't be a remote node: unspecified, multicast or
+    /// link-local addresses, or no port. Loopback addresses only make sense between nodes of the
+    /// same host, so they are kept when we listen on a loopback address too.
+    fn is_routable(addr: &SocketAddr, listen_ip: IpAddr) -> bool {
+        let ip = addr.ip();
+        if addr.port() == 0 || ip.is_unspecified() || ip.is_multicast() {
+            return false;
+        }
+        if ip.is_loopback() && !listen_ip.is_loopback() {
+            return false;
+        }
+        match ip {
+            IpAddr::V4(ip) => !ip.is_broadcast() && !ip.is_link_local(),
+            IpAddr::V6(ip) => !ip.is_unicast_link_local(),
+        }
+    }
+
+    /// Accept errors caused by a single incoming connection, the listener itself is fine.
+    fn is_connection_error(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+        )
+    }
+
+    /// Accept errors that go away once some connections are closed.
+    fn is_resource_exhaustion(err: &io::Error) -> bool {
+        if err.kind() == io::ErrorKind::OutOfMemory {
+            return true;
+        }
+        #[cfg(unix)]
+        if let Some(code) = err.raw_os_error() {
+            return [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM].contains(&code);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::queue::{self, OverflowPolicy};
+    use crate::network::test_utils::{addr, now};
+
+    fn peers_with_status(statuses: &[PeerStatus]) -> HashMap<SocketAddr, Peer> {
+        statuses
+            .iter()
+            .enumerate()
+            .map(|(i, status)| {
+                let mut peer = Peer::from_addr(addr(i as u8 + 1));
+                match status {
+                    PeerStatus::OutConnecting => peer.connecting(),
+                    PeerStatus::OutHandshaking => peer.handshake(true),
+                    PeerStatus::OutAlive => {
+                        peer.handshake(true);
+                        peer.alive(now()).expect("OutHandshaking -> OutAlive");
+                    }
+                    _ => {}
+                }
+                (*peer.addr(), peer)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_select_peers_to_dial() {
+        let mut peers = peers_with_status(&[
+            PeerStatus::OutAlive,
+            PeerStatus::OutConnecting,
+            PeerStatus::Idle,
+            PeerStatus::Idle,
+            PeerStatus::Idle,
+        ]);
+
+        // Only one more attempt allowed
+        let dialed = NetworkActor::select_peers_to_dial(&mut peers, 4, 2, now());
+        assert_eq!(1, dialed.len());
+        assert_eq!(PeerStatus::OutConnecting, peers[&dialed[0]].status());
+
+        // Target already reached by alive peers and attempts
+        assert!(NetworkActor::select_peers_to_dial(&mut peers, 3, 8, now()).is_empty());
+
+        // Peers backing off after a failure wait for their next dial date
+        let now = now();
+        for peer in peers.values_mut() {
+            if peer.status() == PeerStatus::OutConnecting {
+                peer.failed(now, 0).unwrap();
+            }
+        }
+        let dialed = NetworkActor::select_peers_to_dial(&mut peers, 8, 8, now);
+        assert_eq!(2, dialed.len());
+        let later = now + chrono::Duration::seconds(10);
+        let dialed = NetworkActor::select_peers_to_dial(&mut peers, 8, 8, later);
+        assert_eq!(2, dialed.len());
+    }
+
+    #[test]
+    fn test_select_peers_to_dial_banned_ip() {
+        let mut peers = peers_with_status(&[PeerStatus::Idle, PeerStatus::Idle]);
+        peers.get_mut(&addr(1)).unwrap().banned(now());
+        let other_port = SocketAddr::new(addr(1).ip(), 9090);
+        peers.insert(other_port, Peer::from_addr(other_port));
+
+        // The banned host is not dialed on another port
+        assert_eq!(
+            vec![addr(2)],
+            NetworkActor::select_peers_to_dial(&mut peers, 8, 8, now())
+        );
+        assert_eq!(PeerStatus::Idle, peers[&other_port].status());
+    }
+
+    #[test]
+    fn test_accept_incoming() {
+        let mut peers = peers_with_status(&[PeerStatus::Idle]);
+        let first: SocketAddr = "10.0.0.1:50001".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:50002".parse().unwrap();
+
+        assert_eq!(
+            Ok(()),
+            NetworkActor::accept_incoming(&mut peers, first, 3, 1, now())
+        );
+        assert_eq!(PeerStatus::InHandshaking, peers[&first].status());
+        assert_eq!(
+            Err(ConnectionRejection::TooManyIncomingAttempts),
+            NetworkActor::accept_incoming(&mut peers, second, 3, 1, now())
+        );
+        assert!(!peers.contains_key(&second));
+
+        peers.get_mut(&first).unwrap().alive(now()).unwrap();
+        assert_eq!(
+            Err(ConnectionRejection::TooManyIncomingConnections),
+            NetworkActor::accept_incoming(&mut peers, second, 1, 1, now())
+        );
+
+        // Banned peers are rejected whatever the port they connect from
+        let banned = addr(1);
+        peers.get_mut(&banned).unwrap().banned(now());
+        let banned_at = peers[&banned].last_failure();
+        assert_eq!(
+            Err(ConnectionRejection::Banned),
+            NetworkActor::accept_incoming(
+                &mut peers,
+                "10.0.0.1:50003".parse().unwrap(),
+                3,
+                1,
+                now()
+            )
+        );
+        assert!(peers[&banned].last_failure() >= banned_at);
+    }
+
+    #[test]
+    fn test_register_incoming() {
+        let mut peers = peers_with_status(&[PeerStatus::Idle, PeerStatus::Idle]);
+        let our_addr: SocketAddr = "10.0.0.100:8080".parse().unwrap();
+        let connection: SocketAddr = "10.0.0.1:50001".parse().unwrap();
+        NetworkActor::accept_incoming(&mut peers, connection, 8, 8, now()).unwrap();
+
+        // The connection moves to the address the peer listens on
+        assert_eq!(
+            Ok(()),
+            NetworkActor::register_incoming(&mut peers, connection, our_addr, addr(1), now())
+        );
+        assert!(!peers.contains_key(&connection));
+        assert_eq!(PeerStatus::InHandshaking, peers[&addr(1)].status());
+
+        // Another node behind the same IP is another peer
+        let other: SocketAddr = "10.0.0.1:8081".parse().unwrap();
+        assert_eq!(
+            Ok(()),
+            NetworkActor::register_incoming(&mut peers, connection, our_addr, other, now())
+        );
+        assert_eq!(PeerStatus::InHandshaking, peers[&other].status());
+
+        assert_eq!(
+            Err(ConnectionRejection::AlreadyConnected),
+            NetworkActor::register_incoming(&mut peers, connection, our_addr, addr(1), now())
+        );
+        peers.get_mut(&addr(2)).unwrap().mark_self();
+        assert_eq!(
+            Err(ConnectionRejection::SelfConnection),
+            NetworkActor::register_incoming(&mut peers, connection, our_addr, addr(2), now())
+        );
+    }
+
+    #[test]
+    fn test_simultaneous_open() {
+        let mut peers = peers_with_status(&[PeerStatus::OutConnecting, PeerStatus::OutAlive]);
+        let lower = addr(1);
+        let higher = addr(2);
+        let connection: SocketAddr = "10.0.0.1:50001".parse().unwrap();
+        let local: SocketAddr = "10.0.0.100:8080".parse().unwrap();
+
+        // The connection dialed by the lowest address survives
+        assert_eq!(
+            Ok(()),
+            NetworkActor::register_incoming(&mut peers, connection, local, lower, now())
+        );
+        assert_eq!(PeerStatus::InHandshaking, peers[&lower].status());
+        assert_eq!(
+            Err(ConnectionRejection::AlreadyConnected),
+            NetworkActor::register_incoming(&mut peers, connection, lower, higher, now())
+        );
+        assert_eq!(PeerStatus::OutAlive, peers[&higher].status());
+
+        // Our alive outgoing connection is superseded when it loses
+        assert_eq!(
+            Ok(()),
+            NetworkActor::register_incoming(&mut peers, connection, local, higher, now())
+        );
+        assert!(peers
+            .get_mut(&higher)
+            .unwrap()
+            .consume_superseded_outgoing());
+    }
+
+    #[test]
+    fn test_evict_peers() {
+        let mut peers = peers_with_status(&[
+            PeerStatus::OutAlive,
+            PeerStatus::OutHandshaking,
+            PeerStatus::Idle,
+            PeerStatus::Idle,
+        ]);
+        let (alive, failed, untried, banned) = (addr(1), addr(2), addr(3), addr(4));
+        peers.get_mut(&alive).unwrap().closed(now()).unwrap();
+        peers.get_mut(&failed).unwrap().failed(now(), 0).unwrap();
+        peers.get_mut(&banned).unwrap().banned(now());
+        let limits = PeersLimits {
+            max_idle_peers: 1,
+            max_banned_peers: 0,
+        };
+
+        let mut evicted = NetworkActor::evict_peers(&mut peers, limits);
+        evicted.sort();
+        assert_eq!(vec![failed, untried, banned], evicted);
+        assert!(peers.contains_key(&alive));
+    }
+
+    #[test]
+    fn test_rank_peers() {
+        let mut peers = peers_with_status(&[
+            PeerStatus::Idle,
+            PeerStatus::OutAlive,
+            PeerStatus::OutHandshaking,
+            PeerStatus::Idle,
+            PeerStatus::OutHandshaking,
+        ]);
+        peers.get_mut(&addr(3)).unwrap().failed(now(), 0).unwrap();
+        peers.get_mut(&addr(4)).unwrap().banned(now());
+        peers.get_mut(&addr(5)).unwrap().mark_self();
+        let connection: SocketAddr = "10.0.0.6:50006".parse().unwrap();
+        NetworkActor::accept_incoming(&mut peers, connection, 8, 8, now()).unwrap();
+
+        assert_eq!(
+            vec![addr(2), addr(1), addr(3)],
+            NetworkActor::rank_peers(&peers, now())
+        );
+    }
+
+    #[test]
+    fn test_is_routable() {
+        let public: IpAddr = "192.168.0.1".parse().unwrap();
+        for addr in [
+            "0.0.0.0:8080",
+            "127.0.0.1:8080",
+            "[::1]:8080",
+            "224.0.0.1:8080",
+            "169.254.1.1:8080",
+            "192.168.0.204:0",
+        ] {
+            assert!(
+                !NetworkActor::is_routable(&addr.parse().unwrap(), public),
+                "{addr}"
+            );
+        }
+        assert!(NetworkActor::is_routable(
+            &"192.168.0.204:8080".parse().unwrap(),
+            public
+        ));
+
+        // Nodes of the same host gossip their loopback addresses
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(NetworkActor::is_routable(
+            &"127.0.0.1:4002".parse().unwrap(),
+            loopback
+        ));
+        assert!(!NetworkActor::is_routable(
+            &"127.0.0.1:0".parse().unwrap(),
+            loopback
+        ));
+    }
+
+    #[test]
+    fn test_accept_errors() {
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(NetworkActor::is_connection_error(&reset));
+        assert!(!NetworkActor::is_resource_exhaustion(&reset));
+
+        #[cfg(unix)]
+        {
+            let exhausted = io::Error::from_raw_os_error(libc::EMFILE);
+            assert!(!NetworkActor::is_connection_error(&exhausted));
+            assert!(NetworkActor::is_resource_exhaustion(&exhausted));
+        }
+
+        let closed = io::Error::from(io::ErrorKind::NotConnected);
+        assert!(!NetworkActor::is_connection_error(&closed));
+        assert!(!NetworkActor::is_resource_exhaustion(&closed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervise_resets_attempts() {
+        let (queue, _receiver) = queue::bounded(16, OverflowPolicy::Reject);
+        let events = EventSender::new(queue, 16);
+        let (_stop_sender, stop_receiver) = watch::channel(false);
+
+        // The second run stays up long enough to start the backoff over
+        let mut runs = 0;
+        let result =
+            NetworkActor::supervise(BackgroundTask::Listener, 1, events, stop_receiver, || {
+                runs += 1;
+                let uptime = if runs == 2 {
+                    MAX_TASK_RESTART_DELAY * 2
+                } else {
+                    Duration::ZERO
+                };
+                async move {
+                    tokio::time::sleep(uptime).await;
+                    Err(io::Error::other("down").into())
+                }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(3, runs);
+    }
+}
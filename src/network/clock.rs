@@ -0,0 +1,74 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
+
+use crate::network::sync;
+
+/// Source of the dates stored in the peers, such as `last_alive` and `last_failure`
+pub trait Clock: Debug + Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock for tests, starting at a given date. It follows the tokio clock, so with paused time
+/// `tokio::time::advance` moves both the timers and the dates, and `advance` moves the dates only.
+#[derive(Debug)]
+pub struct MockClock {
+    start: DateTime<Utc>,
+    origin: Instant,
+    offset: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        MockClock {
+            start,
+            origin: Instant::now(),
+            offset: Mutex::default(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *sync::lock(&self.offset) += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        let offset = *sync::lock(&self.offset);
+        let elapsed = self.origin.elapsed() + offset;
+        self.start + chrono::Duration::from_std(elapsed).unwrap_or(chrono::Duration::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_clock() {
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let clock = MockClock::new(start);
+        assert_eq!(start, clock.now());
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(start + chrono::Duration::seconds(60), clock.now());
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(start + chrono::Duration::seconds(3660), clock.now());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::network::handshake::HandshakeInfo;
+use crate::network::message::ChannelMessage;
+use displaydoc::Display;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Default maximum size of a frame payload, in bytes
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Size of the length prefix of a frame, in bytes
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+const HANDSHAKE_TAG: u8 = 0;
+const ALIVE_TAG: u8 = 1;
+const ASK_PEERS_LIST_TAG: u8 = 2;
+const PEERS_LIST_TAG: u8 = 3;
+const CLOSE_TAG: u8 = 4;
+
+/// Size of the fixed part of a handshake body: version, listen port and nonce
+const HANDSHAKE_HEADER_SIZE: usize = 4 + 2 + 8;
+
+/// Family byte of the addresses of a peers list
+const IPV4_FAMILY: u8 = 4;
+const IPV6_FAMILY: u8 = 6;
+
+#[derive(Display, Error, Debug)]
+pub enum CodecError {
+    /// Io error: {0}
+    Io(#[from] io::Error),
+    /// Frame of {size} bytes exceeds the maximum of {max} bytes
+    FrameTooLarge { size: usize, max: usize },
+    /// Empty frame
+    EmptyFrame,
+    /// Unknown message tag {0}
+    UnknownTag(u8),
+    /// Invalid message payload: {0}
+    InvalidPayload(String),
+}
+
+/// Encode a message in a frame: a big endian `u32` payload length, followed by the payload made
+/// of a one byte tag and the message body.
+pub fn encode(message: &ChannelMessage) -> Result<Vec<u8>, CodecError> {
+    let encoded;
+    let (tag, body): (u8, &[u8]) = match message {
+        ChannelMessage::Handshake(info) => {
+            encoded = encode_handshake(info);
+            (HANDSHAKE_TAG, &encoded)
+        }
+        ChannelMessage::Alive => (ALIVE_TAG, &[]),
+        ChannelMessage::AskPeersList => (ASK_PEERS_LIST_TAG, &[]),
+        ChannelMessage::PeersList(peers) => {
+            encoded = encode_peers_list(peers)?;
+            (PEERS_LIST_TAG, &encoded)
+        }
+        ChannelMessage::Close => (CLOSE_TAG, &[]),
+    };
+    let size = 1 + body.len();
+    let length = u32::try_from(size).map_err(|_| CodecError::FrameTooLarge {
+        size,
+        max: u32::MAX as usize,
+    })?;
+
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + size);
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.push(tag);
+    frame.extend_from_slice(body);
+
+    Ok(frame)
+}
+
+/// Decode the payload of a frame, without its length prefix.
+pub fn decode(payload: &[u8]) -> Result<ChannelMessage, CodecError> {
+    let (tag, body) = payload.split_first().ok_or(CodecError::EmptyFrame)?;
+
+    match *tag {
+        HANDSHAKE_TAG => decode_handshake(body).map(ChannelMessage::Handshake),
+        ALIVE_TAG => Ok(ChannelMessage::Alive),
+        ASK_PEERS_LIST_TAG => Ok(ChannelMessage::AskPeersList),
+        PEERS_LIST_TAG => decode_peers_list(body).map(ChannelMessage::PeersList),
+        CLOSE_TAG => Ok(ChannelMessage::Close),
+        tag => Err(CodecError::UnknownTag(tag)),
+    }
+}
+
+fn encode_handshake(info: &HandshakeInfo) -> Vec<u8> {
+    let mut body = Vec::with_capacity(HANDSHAKE_HEADER_SIZE + info.network_id.len());
+    body.extend_from_slice(&info.version.to_be_bytes());
+    body.extend_from_slice(&info.listen_port.to_be_bytes());
+    body.extend_from_slice(&info.nonce.to_be_bytes());
+    body.extend_from_slice(info.network_id.as_bytes());
+
+    body
+}
+
+fn decode_handshake(body: &[u8]) -> Result<HandshakeInfo, CodecError> {
+    if body.len() < HANDSHAKE_HEADER_SIZE {
+        return Err(CodecError::InvalidPayload(format!(
+            "handshake of {} bytes",
+            body.len()
+        )));
+    }
+    let (version, rest) = body.split_at(4);
+    let (listen_port, rest) = rest.split_at(2);
+    let (nonce, network_id) = rest.split_at(8);
+
+    Ok(HandshakeInfo {
+        version: u32::from_be_bytes(version.try_into().expect("4 bytes")),
+        listen_port: u16::from_be_bytes(listen_port.try_into().expect("2 bytes")),
+        nonce: u64::from_be_bytes(nonce.try_into().expect("8 bytes")),
+        network_id: String::from_utf8(network_id.to_vec())
+            .map_err(|err| CodecError::InvalidPayload(err.to_string()))?,
+    })
+}
+
+/// A big endian `u16` count followed by each address: its family byte, 4 or 16 IP bytes, and a
+/// big endian `u16` port.
+fn encode_peers_list(peers: &[SocketAddr]) -> Result<Vec<u8>, CodecError> {
+    let count = u16::try_from(peers.len()).map_err(|_| {
+        CodecError::InvalidPayload(format!("peers list of {} addresses", peers.len()))
+    })?;
+    let mut body = Vec::with_capacity(2 + peers.len() * (1 + 16 + 2));
+    body.extend_from_slice(&count.to_be_bytes());
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                body.push(IPV4_FAMILY);
+                body.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                body.push(IPV6_FAMILY);
+                body.extend_from_slice(&ip.octets());
+            }
+        }
+        body.extend_from_slice(&peer.port().to_be_bytes());
+    }
+
+    Ok(body)
+}
+
+fn decode_peers_list(body: &[u8]) -> Result<Vec<SocketAddr>, CodecError> {
+    let truncated = || CodecError::InvalidPayload("truncated peers list".to_string());
+    let (count, mut rest) = body.split_first_chunk::<2>().ok_or_else(truncated)?;
+    let count = u16::from_be_bytes(*count);
+
+    let mut peers = Vec::with_capacity(count.into());
+    for _ in 0..count {
+        let (family, tail) = rest.split_first().ok_or_else(truncated)?;
+        let (ip, tail) = match *family {
+            IPV4_FAMILY => {
+                let (ip, tail) = tail.split_first_chunk::<4>().ok_or_else(truncated)?;
+                (IpAddr::V4(Ipv4Addr::from(*ip)), tail)
+            }
+            IPV6_FAMILY => {
+                let (ip, tail) = tail.split_first_chunk::<16>().ok_or_else(truncated)?;
+                (IpAddr::V6(Ipv6Addr::from(*ip)), tail)
+            }
+            family => {
+                return Err(CodecError::InvalidPayload(format!(
+                    "unknown address family {}",
+                    family
+                )))
+            }
+        };
+        let (port, tail) = tail.split_first_chunk::<2>().ok_or_else(truncated)?;
+        peers.push(SocketAddr::new(ip, u16::from_be_bytes(*port)));
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return Err(CodecError::InvalidPayload(format!(
+            "{} trailing bytes after the peers list",
+            rest.len()
+        )));
+    }
+
+    Ok(peers)
+}
+
+/// Write a message as a single frame.
+pub async fn write_message<W>(writer: &mut W, message: &ChannelMessage) -> Result<(), CodecError>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&encode(message)?).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Read the next frame and decode its message. Frames bigger than `max_frame_size` are rejected
+/// before their payload is read.
+pub async fn read_message<R>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> Result<ChannelMessage, CodecError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut length = [0u8; LENGTH_PREFIX_SIZE];
+    reader.read_exact(&mut length).await?;
+    let size = u32::from_be_bytes(length) as usize;
+    if size > max_frame_size {
+        return Err(CodecError::FrameTooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+
+    let mut payload = vec![0u8; size];
+    reader.read_exact(&mut payload).await?;
+
+    decode(&payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let messages = [
+            ChannelMessage::Handshake(HandshakeInfo {
+                version: 1,
+                network_id: "test".to_string(),
+                listen_port: 8080,
+                nonce: 42,
+            }),
+            ChannelMessage::Alive,
+            ChannelMessage::AskPeersList,
+            ChannelMessage::PeersList(vec![
+                "192.168.1.1:31245".parse().unwrap(),
+                "[2001:db8::1]:31245".parse().unwrap(),
+            ]),
+            ChannelMessage::Close,
+        ];
+
+        for message in &messages {
+            write_message(&mut client, message).await.unwrap();
+            let received = read_message(&mut server, MAX_FRAME_SIZE).await.unwrap();
+            assert_eq!(format!("{:?}", message), format!("{:?}", received));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let message = ChannelMessage::PeersList(vec!["192.168.1.1:31245".parse().unwrap(); 4]);
+
+        write_message(&mut client, &message).await.unwrap();
+        assert!(matches!(
+            read_message(&mut server, 16).await,
+            Err(CodecError::FrameTooLarge { size: 31, max: 16 })
+        ));
+    }
+
+    #[test]
+    fn test_decode_invalid_frames() {
+        assert!(matches!(decode(&[]), Err(CodecError::EmptyFrame)));
+        assert!(matches!(decode(&[42]), Err(CodecError::UnknownTag(42))));
+        assert!(matches!(
+            decode(&[HANDSHAKE_TAG, 0, 0, 0, 1]),
+            Err(CodecError::InvalidPayload(_))
+        ));
+        // Truncated address, unknown family, trailing bytes
+        for body in [
+            &[PEERS_LIST_TAG, 0xff][..],
+            &[PEERS_LIST_TAG, 0, 1, IPV4_FAMILY, 127, 0, 0, 1, 0x1f],
+            &[PEERS_LIST_TAG, 0, 1, 5, 127, 0, 0, 1, 0x1f, 0x90],
+            &[PEERS_LIST_TAG, 0, 0, 0],
+        ] {
+            assert!(
+                matches!(decode(body), Err(CodecError::InvalidPayload(_))),
+                "{body:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_peers_list_round_trip() {
+        let peers: Vec<SocketAddr> = vec![
+            "127.0.0.1:8080".parse().unwrap(),
+            "[::1]:8080".parse().unwrap(),
+            "[2001:db8::1]:31245".parse().unwrap(),
+        ];
+        let frame = encode(&ChannelMessage::PeersList(peers.clone())).unwrap();
+        assert_eq!(
+            // Length prefix, tag, count, two IPv4 and one IPv6 address
+            LENGTH_PREFIX_SIZE + 1 + 2 + 7 + 19 + 19,
+            frame.len()
+        );
+        match decode(&frame[LENGTH_PREFIX_SIZE..]).unwrap() {
+            ChannelMessage::PeersList(decoded) => assert_eq!(peers, decoded),
+            other => panic!("Unexpected message {:?}", other),
+        }
+
+        let empty = encode(&ChannelMessage::PeersList(Vec::new())).unwrap();
+        assert!(matches!(
+            decode(&empty[LENGTH_PREFIX_SIZE..]),
+            Ok(ChannelMessage::PeersList(peers)) if peers.is_empty()
+        ));
+        assert!(matches!(
+            encode(&ChannelMessage::PeersList(vec![peers[0]; 1 << 16])),
+            Err(CodecError::InvalidPayload(_))
+        ));
+    }
+}
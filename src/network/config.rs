@@ -0,0 +1,460 @@
+use crate::network::queue::OverflowPolicy;
+use displaydoc::Display;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Prefix of the environment variables overriding the config, followed by the upper case name
+/// of the field, e.g. `MASSA_NETWORK_LISTEN_PORT`
+pub const ENV_PREFIX: &str = "MASSA_NETWORK_";
+/// Largest `event_queue_capacity`, the subscribers channel allocates all of it upfront
+pub const MAX_EVENT_QUEUE_CAPACITY: usize = 65_536;
+
+#[derive(Display, Error, Debug)]
+pub enum NetworkConfigError {
+    /// Unable to read the config file: {0}
+    Io(#[from] io::Error),
+    /// JSON config does not have correct format: {0}
+    Json(#[from] serde_json::Error),
+    /// TOML config does not have correct format: {0}
+    Toml(#[from] toml::de::Error),
+    /// Unsupported config file {0}, expected a .toml or .json file
+    UnsupportedFormat(String),
+    /// Invalid value {value:?} for environment variable {name}
+    InvalidEnvVar { name: String, value: String },
+    /// Invalid config: {0}
+    Invalid(String),
+}
+
+/// Settings of the `NetworkController`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// JSON file where the peers are loaded from and dumped to
+    pub peers_file: String,
+    /// Address on which we accept incoming connections
+    pub listen_ip: IpAddr,
+    /// Port on which we accept incoming connections, and advertise in the handshake
+    pub listen_port: u16,
+    /// Number of OutAlive peers we try to keep
+    pub target_outgoing_connections: usize,
+    /// Maximum number of InAlive and InHandshaking peers
+    pub max_incoming_connections: usize,
+    /// Maximum number of OutConnecting and OutHandshaking peers
+    pub max_simultaneous_outgoing_connection_attempts: usize,
+    /// Maximum number of InHandshaking peers
+    pub max_simultaneous_incoming_connection_attempts: usize,
+    /// Maximum number of Idle peers kept in the peer table
+    pub max_idle_peers: usize,
+    /// Maximum number of Banned peers kept in the peer table
+    pub max_banned_peers: usize,
+    /// Delay between two dumps of the peers file, when it changed
+    pub peer_file_dump_interval_seconds: u64,
+    /// Peers on another network are rejected during the handshake
+    pub network_id: String,
+    pub handshake_timeout_seconds: u64,
+    /// How many times a failed listener or connector task is restarted, with an exponential
+    /// backoff, before the controller gives up
+    pub task_restart_attempts: u32,
+    /// Maximum number of connections and events waiting for `wait_event`, and of events waiting
+    /// for each subscriber
+    pub event_queue_capacity: usize,
+    /// What happens to new connections and events when the queue of `wait_event` is full
+    pub event_queue_overflow_policy: OverflowPolicy,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            peers_file: "peers.json".to_string(),
+            listen_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen_port: 8080,
+            target_outgoing_connections: 8,
+            max_incoming_connections: 16,
+            max_simultaneous_outgoing_connection_attempts: 16,
+            max_simultaneous_incoming_connection_attempts: 16,
+            max_idle_peers: 16,
+            max_banned_peers: 16,
+            peer_file_dump_interval_seconds: 2,
+            network_id: "massa-test".to_string(),
+            handshake_timeout_seconds: 5,
+            task_restart_attempts: 3,
+            event_queue_capacity: 1024,
+            event_queue_overflow_policy: OverflowPolicy::Reject,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn builder() -> NetworkConfigBuilder {
+        NetworkConfigBuilder::default()
+    }
+
+    /// Config from the given file, or the defaults, overridden by the environment variables. It
+    /// isn't validated, so that other overrides can still fix it.
+    pub fn load(path: Option<&Path>) -> Result<Self, NetworkConfigError> {
+        let config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        config.with_env_overrides(std::env::vars_os())
+    }
+
+    /// Read a TOML or JSON config file, depending on its extension. Missing fields keep their
+    /// default value.
+    pub fn from_file(path: &Path) -> Result<Self, NetworkConfigError> {
+        let data = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Ok(toml::from_str(&data)?),
+            Some("json") => Ok(serde_json::from_str(&data)?),
+            _ => Err(NetworkConfigError::UnsupportedFormat(
+                path.display().to_string(),
+            )),
+        }
+    }
+
+    /// Override the fields set by `MASSA_NETWORK_*` variables. The other variables are ignored,
+    /// even if they are not valid UTF-8.
+    pub fn with_env_overrides<I, K, V>(mut self, vars: I) -> Result<Self, NetworkConfigError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<OsString>,
+        V: Into<OsString>,
+    {
+        for (name, value) in vars {
+            let name: OsString = name.into();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let Some(field) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let value =
+                value
+                    .into()
+                    .into_string()
+                    .map_err(|value| NetworkConfigError::InvalidEnvVar {
+                        name: name.to_string(),
+                        value: value.to_string_lossy().into_owned(),
+                    })?;
+            match field {
+                "PEERS_FILE" => self.peers_file = value,
+                "LISTEN_IP" => self.listen_ip = parse_env(name, value)?,
+                "LISTEN_PORT" => self.listen_port = parse_env(name, value)?,
+                "TARGET_OUTGOING_CONNECTIONS" => {
+                    self.target_outgoing_connections = parse_env(name, value)?
+                }
+                "MAX_INCOMING_CONNECTIONS" => {
+                    self.max_incoming_connections = parse_env(name, value)?
+                }
+                "MAX_SIMULTANEOUS_OUTGOING_CONNECTION_ATTEMPTS" => {
+                    self.max_simultaneous_outgoing_connection_attempts = parse_env(name, value)?
+                }
+                "MAX_SIMULTANEOUS_INCOMING_CONNECTION_ATTEMPTS" => {
+                    self.max_simultaneous_incoming_connection_attempts = parse_env(name, value)?
+                }
+                "MAX_IDLE_PEERS" => self.max_idle_peers = parse_env(name, value)?,
+                "MAX_BANNED_PEERS" => self.max_banned_peers = parse_env(name, value)?,
+                "PEER_FILE_DUMP_INTERVAL_SECONDS" => {
+                    self.peer_file_dump_interval_seconds = parse_env(name, value)?
+                }
+                "NETWORK_ID" => self.network_id = value,
+                "HANDSHAKE_TIMEOUT_SECONDS" => {
+                    self.handshake_timeout_seconds = parse_env(name, value)?
+                }
+                "TASK_RESTART_ATTEMPTS" => self.task_restart_attempts = parse_env(name, value)?,
+                "EVENT_QUEUE_CAPACITY" => self.event_queue_capacity = parse_env(name, value)?,
+                "EVENT_QUEUE_OVERFLOW_POLICY" => {
+                    self.event_queue_overflow_policy = parse_env(name, value)?
+                }
+                _ => warn!("Unknown config variable {}", name),
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Reject values the controller can't work with, or which contradict each other.
+    pub fn validate(&self) -> Result<(), NetworkConfigError> {
+        let invalid = |reason: &str| Err(NetworkConfigError::Invalid(reason.to_string()));
+
+        if self.peers_file.is_empty() {
+            return invalid("peers_file is empty");
+        }
+        // Other peers would never dial us back on port 0
+        if self.listen_port == 0 {
+            return invalid("listen_port can't be 0");
+        }
+        if self.network_id.is_empty() {
+            return invalid("network_id is empty");
+        }
+        if self.peer_file_dump_interval_seconds == 0 {
+            return invalid("peer_file_dump_interval_seconds can't be 0");
+        }
+        if self.handshake_timeout_seconds == 0 {
+            return invalid("handshake_timeout_seconds can't be 0");
+        }
+        if self.event_queue_capacity == 0 {
+            return invalid("event_queue_capacity can't be 0");
+        }
+        if self.event_queue_capacity > MAX_EVENT_QUEUE_CAPACITY {
+            return invalid("event_queue_capacity exceeds MAX_EVENT_QUEUE_CAPACITY");
+        }
+        if self.target_outgoing_connections > 0
+            && self.max_simultaneous_outgoing_connection_attempts == 0
+        {
+            return invalid(
+                "max_simultaneous_outgoing_connection_attempts can't be 0 with outgoing connections",
+            );
+        }
+        if self.max_simultaneous_incoming_connection_attempts > self.max_incoming_connections {
+            return invalid(
+                "max_simultaneous_incoming_connection_attempts exceeds max_incoming_connections",
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str, value: String) -> Result<T, NetworkConfigError> {
+    value
+        .parse()
+        .map_err(|_| NetworkConfigError::InvalidEnvVar {
+            name: name.to_string(),
+            value,
+        })
+}
+
+/// Build a `NetworkConfig` from the defaults, validated by `build`
+#[derive(Debug, Default)]
+pub struct NetworkConfigBuilder {
+    config: NetworkConfig,
+}
+
+impl NetworkConfigBuilder {
+    pub fn peers_file(mut self, peers_file: &str) -> Self {
+        self.config.peers_file = peers_file.to_string();
+        self
+    }
+
+    pub fn listen_ip(mut self, listen_ip: IpAddr) -> Self {
+        self.config.listen_ip = listen_ip;
+        self
+    }
+
+    pub fn listen_port(mut self, listen_port: u16) -> Self {
+        self.config.listen_port = listen_port;
+        self
+    }
+
+    pub fn target_outgoing_connections(mut self, target: usize) -> Self {
+        self.config.target_outgoing_connections = target;
+        self
+    }
+
+    pub fn max_incoming_connections(mut self, max: usize) -> Self {
+        self.config.max_incoming_connections = max;
+        self
+    }
+
+    pub fn max_simultaneous_outgoing_connection_attempts(mut self, max: usize) -> Self {
+        self.config.max_simultaneous_outgoing_connection_attempts = max;
+        self
+    }
+
+    pub fn max_simultaneous_incoming_connection_attempts(mut self, max: usize) -> Self {
+        self.config.max_simultaneous_incoming_connection_attempts = max;
+        self
+    }
+
+    pub fn max_idle_peers(mut self, max: usize) -> Self {
+        self.config.max_idle_peers = max;
+        self
+    }
+
+    pub fn max_banned_peers(mut self, max: usize) -> Self {
+        self.config.max_banned_peers = max;
+        self
+    }
+
+    pub fn peer_file_dump_interval_seconds(mut self, seconds: u64) -> Self {
+        self.config.peer_file_dump_interval_seconds = seconds;
+        self
+    }
+
+    pub fn network_id(mut self, network_id: &str) -> Self {
+        self.config.network_id = network_id.to_string();
+        self
+    }
+
+    pub fn handshake_timeout_seconds(mut self, seconds: u64) -> Self {
+        self.config.handshake_timeout_seconds = seconds;
+        self
+    }
+
+    pub fn task_restart_attempts(mut self, attempts: u32) -> Self {
+        self.config.task_restart_attempts = attempts;
+        self
+    }
+
+    pub fn event_queue_capacity(mut self, capacity: usize) -> Self {
+        self.config.event_queue_capacity = capacity;
+        self
+    }
+
+    pub fn event_queue_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.event_queue_overflow_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> Result<NetworkConfig, NetworkConfigError> {
+        self.config.validate()?;
+
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_utils::TempFile;
+
+    #[test]
+    fn test_builder_and_validate() {
+        assert!(NetworkConfig::default().validate().is_ok());
+
+        let config = NetworkConfig::builder()
+            .listen_port(9090)
+            .network_id("other")
+            .build()
+            .expect("A valid config");
+        assert_eq!(9090, config.listen_port);
+        assert_eq!("other", config.network_id);
+
+        for builder in [
+            NetworkConfig::builder().peer_file_dump_interval_seconds(0),
+            NetworkConfig::builder().listen_port(0),
+            NetworkConfig::builder().network_id(""),
+            NetworkConfig::builder().event_queue_capacity(0),
+            NetworkConfig::builder().event_queue_capacity(MAX_EVENT_QUEUE_CAPACITY + 1),
+            NetworkConfig::builder().max_simultaneous_outgoing_connection_attempts(0),
+            NetworkConfig::builder()
+                .max_incoming_connections(4)
+                .max_simultaneous_incoming_connection_attempts(8),
+        ] {
+            assert!(matches!(
+                builder.build(),
+                Err(NetworkConfigError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_from_file() {
+        let toml_file = TempFile::new("network.toml");
+        let toml_path = toml_file.path();
+        fs::write(
+            toml_path,
+            "listen_port = 9090\nnetwork_id = \"other\"\nevent_queue_overflow_policy = \"block\"\n",
+        )
+        .unwrap();
+        let json_file = TempFile::new("network.json");
+        fs::write(json_file.path(), "{\"max_idle_peers\": 4}").unwrap();
+
+        let config = NetworkConfig::from_file(toml_path).expect("A TOML config");
+        assert_eq!(9090, config.listen_port);
+        assert_eq!("other", config.network_id);
+        assert_eq!(OverflowPolicy::Block, config.event_queue_overflow_policy);
+        // Missing fields keep their default value
+        assert_eq!(
+            NetworkConfig::default().max_idle_peers,
+            config.max_idle_peers
+        );
+
+        let config = NetworkConfig::from_file(json_file.path()).expect("A JSON config");
+        assert_eq!(4, config.max_idle_peers);
+
+        fs::write(toml_path, "listen_prot = 9090\n").unwrap();
+        assert!(matches!(
+            NetworkConfig::from_file(toml_path),
+            Err(NetworkConfigError::Toml(_))
+        ));
+        let yaml_path = toml_path.with_extension("yaml");
+        fs::write(&yaml_path, "listen_port: 9090\n").unwrap();
+        assert!(matches!(
+            NetworkConfig::from_file(&yaml_path),
+            Err(NetworkConfigError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let vars = [
+            ("MASSA_NETWORK_LISTEN_PORT", "9090"),
+            ("MASSA_NETWORK_NETWORK_ID", "other"),
+            ("MASSA_NETWORK_EVENT_QUEUE_OVERFLOW_POLICY", "drop_oldest"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        let config = NetworkConfig::default()
+            .with_env_overrides(vars)
+            .expect("Valid overrides");
+        assert_eq!(9090, config.listen_port);
+        assert_eq!("other", config.network_id);
+        assert_eq!(
+            OverflowPolicy::DropOldest,
+            config.event_queue_overflow_policy
+        );
+
+        let vars = [("MASSA_NETWORK_MAX_IDLE_PEERS".to_string(), "-1".to_string())];
+        assert!(matches!(
+            NetworkConfig::default().with_env_overrides(vars),
+            Err(NetworkConfigError::InvalidEnvVar { .. })
+        ));
+
+        let vars = [(
+            "MASSA_NETWORK_EVENT_QUEUE_CAPACITY".to_string(),
+            usize::MAX.to_string(),
+        )];
+        let config = NetworkConfig::default()
+            .with_env_overrides(vars)
+            .expect("Valid overrides");
+        assert!(matches!(
+            config.validate(),
+            Err(NetworkConfigError::Invalid(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_env_overrides_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let not_utf8 = || OsString::from_vec(vec![0xff, 0xfe]);
+
+        // Only our own variables have to be valid UTF-8
+        let vars = [
+            (not_utf8(), OsString::from("value")),
+            (OsString::from("OTHER"), not_utf8()),
+        ];
+        assert_eq!(
+            NetworkConfig::default(),
+            NetworkConfig::default().with_env_overrides(vars).unwrap()
+        );
+
+        let vars = [(OsString::from("MASSA_NETWORK_NETWORK_ID"), not_utf8())];
+        assert!(matches!(
+            NetworkConfig::default().with_env_overrides(vars),
+            Err(NetworkConfigError::InvalidEnvVar { name, .. }) if name == "MASSA_NETWORK_NETWORK_ID"
+        ));
+    }
+}
@@ -1,232 +1,659 @@
-use std::collections::HashMap;
 use std::io;
-use std::net::IpAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use displaydoc::Display;
-use log::{error, info, warn};
 use thiserror::Error;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::{mpsc, RwLock};
-use tokio::task;
+use tokio::sync::mpsc;
 
+use crate::network::actor::{self, Command, Feedback, NetworkActor};
+use crate::network::clock::{SharedClock, SystemClock};
+use crate::network::config::{NetworkConfig, NetworkConfigError};
 use crate::network::controller::NetworkControllerEvent::CandidateConnection;
-use crate::network::file::{PeersFileController, PeersFileControllerError};
-use crate::network::message::ChannelMessage;
-use crate::network::message::ChannelMessage::Connection;
-use crate::network::peer::{Peer, PeerError};
+use crate::network::event::{EventKind, EventSender, EventStream, NetworkEvent, QueueItem};
+use crate::network::file::PeersFileControllerError;
+use crate::network::peer::PeerError;
+use crate::network::queue::{self, QueueReceiver};
+use crate::network::sync;
+use crate::network::transport::{BoxedConnection, TcpTransport, Transport};
 
 #[derive(Display, Error, Debug)]
 pub enum NetworkControllerError {
+    /// Invalid network config: {0}
+    Config(#[from] NetworkConfigError),
     /// Error with the file controller: {0}
     FileController(#[from] PeersFileControllerError),
     /// Io error: {0}
     Io(#[from] io::Error),
-    /// Impossible to acquire lock over a RwLock
-    RwLockPoisoned,
     /// Cannot manage peer {0}
     PeerError(#[from] PeerError),
     /// Error sending a message in the channel
-    ChannelError { peer_ip: IpAddr },
+    ChannelError { peer_addr: SocketAddr },
+    /// The event queue is full, the connection with {peer_addr} is dropped
+    QueueFull { peer_addr: SocketAddr },
     /// The channel is closed
     ClosedChanel,
+    /// Unknown peer {0}
+    UnknownPeer(SocketAddr),
+    /// Peer {0} is not connected, it can't hold a socket
+    NotConnected(SocketAddr),
+    /// Background tasks failed: {0:?}
+    TasksFailed(Vec<NetworkControllerError>),
+    /// The {task} stopped after failing: {error}
+    TaskStopped { task: BackgroundTask, error: String },
 }
 
 impl From<NetworkControllerError> for io::Error {
     fn from(err: NetworkControllerError) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, err)
+        io::Error::other(err)
     }
 }
 
+/// Maximum sizes of the Idle and Banned parts of the peer table
+#[derive(Debug, Clone, Copy)]
+pub struct PeersLimits {
+    pub max_idle_peers: usize,
+    pub max_banned_peers: usize,
+}
+
+/// Handle to the network actor, which owns the peer table and the background tasks. Clones are
+/// cheap and can be used from any task; the actor shuts down when `shutdown` is called or the
+/// last handle is dropped.
+#[derive(Clone)]
 pub struct NetworkController {
-    file_controller: Arc<PeersFileController>,
-    peers: Arc<RwLock<HashMap<IpAddr, Peer>>>,
-    target_outgoing_connections: HashMap<IpAddr, Peer>,
-    listen_port: u16,
-    connect_to_peers_handle: task::JoinHandle<()>,
-    channel_sender: UnboundedSender<ChannelMessage>,
-    channel_receiver: UnboundedReceiver<ChannelMessage>,
+    commands: mpsc::Sender<Command>,
+    receiver: Arc<QueueReceiver<QueueItem>>,
+    /// Set once a background task failed for good
+    stopped_task: Arc<Mutex<Option<(BackgroundTask, String)>>>,
 }
 
 impl NetworkController {
-    pub async fn new(
-        peers_file: &str,
-        listen_port: u16,
-        target_outgoing_connections: HashMap<IpAddr, Peer>,
-        max_incoming_connections: usize,
-        max_simultaneous_outgoing_connection_attempts: usize,
-        max_simultaneous_incoming_connection_attempts: usize,
-        max_idle_peers: usize,
-        max_banned_peers: usize,
-        peer_file_dump_interval_seconds: u64,
+    pub async fn new(config: NetworkConfig) -> Result<Self, NetworkControllerError> {
+        Self::builder(config).start().await
+    }
+
+    /// Controller with another transport or clock than TCP and the system clock.
+    pub fn builder(config: NetworkConfig) -> NetworkControllerBuilder {
+        NetworkControllerBuilder {
+            config,
+            transport: Arc::new(TcpTransport),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    async fn start(
+        config: NetworkConfig,
+        transport: Arc<dyn Transport>,
+        clock: SharedClock,
     ) -> Result<Self, NetworkControllerError> {
-        let file_controller = Arc::new(PeersFileController::new(peers_file));
-
-        // Read json and create peers
-        let peer_list = file_controller.read_file()?;
-        let peers: Arc<RwLock<HashMap<IpAddr, Peer>>> = Arc::new(RwLock::new(peer_list));
-
-        let peers_clone_file_controller = peers.clone();
-        let peers_clone_task_connect = peers.clone();
-
-        // Create the file dumper worker
-        let file_controller_clone = file_controller.clone();
-        tokio::spawn(async move {
-            info!("Starting file worker");
-            let mut interval =
-                tokio::time::interval(Duration::from_secs(peer_file_dump_interval_seconds));
-            loop {
-                interval.tick().await;
-                let _ = file_controller_clone.write_file(peers_clone_file_controller.as_ref());
-            }
-        });
-
-        let (channel_sender, mut channel_receiver) = mpsc::unbounded_channel::<ChannelMessage>();
-
-        // Create task for connecting to peers
-        let channel_sender_connect_peers = channel_sender.clone();
-        let connect_to_peers_handle = task::spawn(async move {
-            Self::connect_to_peers(
-                peers_clone_task_connect,
-                listen_port,
-                channel_sender_connect_peers,
-            )
-            .await;
-        });
-
-        // Create task for listening new peers
-        let channel_listen_connect_peers = channel_sender.clone();
-        let listen_connecting_peer = task::spawn(async move {
-            Self::listen_new_peers(listen_port, channel_listen_connect_peers).await;
-        });
+        config.validate()?;
+        let (channel_sender, channel_receiver) = queue::bounded(
+            config.event_queue_capacity,
+            config.event_queue_overflow_policy,
+        );
+        let events = EventSender::new(channel_sender, config.event_queue_capacity);
+        let receiver = Arc::new(channel_receiver);
+        let (commands, command_receiver) = mpsc::channel(actor::COMMAND_CAPACITY);
+        NetworkActor::spawn(
+            config,
+            transport,
+            clock,
+            events,
+            receiver.clone(),
+            command_receiver,
+        )?;
 
         Ok(Self {
-            file_controller,
-            peers,
-            target_outgoing_connections,
-            listen_port,
-            connect_to_peers_handle,
-            channel_sender,
-            channel_receiver,
+            commands,
+            receiver,
+            stopped_task: Arc::default(),
         })
     }
 
+    /// Stop accepting and dialing peers, give the in-flight handshakes some time to finish, send
+    /// `Close` to the connected peers, then flush the peers file. The errors of the background
+    /// tasks are returned. The other handles fail with `ClosedChanel` from then on.
+    pub async fn shutdown(self) -> Result<(), NetworkControllerError> {
+        actor::request(&self.commands, |reply| Command::Shutdown { reply }).await?
+    }
+
+    /// Add a peer from its `ip:port` address, or its bare IP if it listens on our port. A known
+    /// peer keeps its state; `socket` can only be given for a connected peer, which then holds it.
     pub async fn add_peer(
-        &mut self,
-        ip: String,
-        socket: Option<TcpStream>,
+        &self,
+        addr: String,
+        socket: Option<BoxedConnection>,
     ) -> Result<(), NetworkControllerError> {
-        let mut peer = Peer::new(&ip)?;
-        peer.socket = socket;
-        self.peers.write().await.insert(*peer.ip(), peer);
-        self.file_controller.changed();
-
-        Ok(())
-    }
-
-    pub fn remove_peer(&self) {
-        todo!()
-    }
-
-    pub async fn wait_event(&mut self) -> Result<NetworkControllerEvent, NetworkControllerError> {
-        if let Some(event) = self.channel_receiver.recv().await {
-            match event {
-                Connection {
-                    ip,
-                    socket,
-                    is_outgoing,
-                } => Ok(CandidateConnection {
-                    ip: ip.to_string(),
-                    socket: socket,
-                    is_outgoing,
-                }),
-                _ => todo!(),
-            }
-        } else {
-            Err(NetworkControllerError::ClosedChanel)
+        actor::request(&self.commands, |reply| Command::AddPeer {
+            addr,
+            socket,
+            reply,
+        })
+        .await?
+    }
+
+    /// Forget the peer, closing its connection if the controller holds it.
+    pub async fn remove_peer(&self, addr: &SocketAddr) -> Result<(), NetworkControllerError> {
+        let addr = *addr;
+        actor::request(&self.commands, |reply| Command::RemovePeer { addr, reply }).await?
+    }
+
+    /// Next event of the controller. Fails once a background task stopped for good, after the
+    /// `TaskFailed` event reporting it. When several handles wait, each event goes to one of them.
+    pub async fn wait_event(&self) -> Result<NetworkControllerEvent, NetworkControllerError> {
+        if let Some((task, error)) = &*self.stopped_task() {
+            return Err(NetworkControllerError::TaskStopped {
+                task: *task,
+                error: error.clone(),
+            });
         }
+        let Some(item) = self.receiver.recv().await else {
+            return Err(NetworkControllerError::ClosedChanel);
+        };
+        let event = match item {
+            QueueItem::Connection {
+                addr,
+                socket,
+                is_outgoing,
+            } => CandidateConnection {
+                addr,
+                socket,
+                is_outgoing,
+            },
+            QueueItem::Event(event) => {
+                if let NetworkEvent::TaskFailed {
+                    task,
+                    error,
+                    restarting: false,
+                } = &event
+                {
+                    *self.stopped_task() = Some((*task, error.clone()));
+                }
+                NetworkControllerEvent::Event(event)
+            }
+        };
+
+        Ok(event)
+    }
+
+    fn stopped_task(&self) -> std::sync::MutexGuard<'_, Option<(BackgroundTask, String)>> {
+        sync::lock(&self.stopped_task)
     }
 
-    pub async fn listen_new_peers(
-        listen_port: u16,
-        sender: UnboundedSender<ChannelMessage>,
+    /// Connections and events dropped or rejected since the start because the queue of
+    /// `wait_event` was full
+    pub fn dropped_count(&self) -> u64 {
+        self.receiver.dropped()
+    }
+
+    /// Independent stream of the events, of all kinds or of the given `kinds`, for consumers
+    /// other than the `wait_event` loop. The connections only go to `wait_event`.
+    pub async fn subscribe(
+        &self,
+        kinds: Option<Vec<EventKind>>,
+    ) -> Result<EventStream, NetworkControllerError> {
+        actor::request(&self.commands, |reply| Command::Subscribe { kinds, reply }).await
+    }
+
+    /// Set the peer in InAlive or OutAlive status once the handshake is done, or refresh its
+    /// `last_alive` if it is already alive.
+    pub async fn feedback_peer_alive(
+        &self,
+        addr: &SocketAddr,
     ) -> Result<(), NetworkControllerError> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", listen_port)).await?;
-
-        loop {
-            let (socket, addr) = listener.accept().await?;
-
-            sender
-                .send(Connection {
-                    ip: addr.ip(),
-                    socket: socket,
-                    is_outgoing: false,
-                })
-                .map_err(|err| NetworkControllerError::ChannelError { peer_ip: addr.ip() })?;
-        }
+        self.feedback(addr, Feedback::Alive).await
     }
 
-    pub async fn connect_to_peers(
-        peers: Arc<RwLock<HashMap<IpAddr, Peer>>>,
-        listen_port: u16,
-        sender: UnboundedSender<ChannelMessage>,
+    /// Ban the peer, whatever its status.
+    pub async fn feedback_peer_banned(
+        &self,
+        addr: &SocketAddr,
     ) -> Result<(), NetworkControllerError> {
-        for peer in peers.write().await.iter_mut() {
-            peer.1.connecting();
-            if let Ok(socket) = TcpStream::connect(format!(
-                "{}:{}",
-                peer.0.to_string(),
-                listen_port.to_string()
-            ))
-            .await
-            {
-                sender
-                    .send(Connection {
-                        ip: *peer.0,
-                        socket,
-                        is_outgoing: true,
-                    })
-                    .map_err(|err| NetworkControllerError::ChannelError { peer_ip: *peer.0 })?;
-            } else {
-                peer.1.idle();
-            }
-        }
+        self.feedback(addr, Feedback::Banned).await
+    }
 
-        Ok(())
+    /// The connection or the handshake failed, the peer goes back to Idle.
+    pub async fn feedback_peer_failed(
+        &self,
+        addr: &SocketAddr,
+    ) -> Result<(), NetworkControllerError> {
+        self.feedback(addr, Feedback::Failed).await
     }
 
-    pub fn feedback_peer_alive(&self, ip: &IpAddr) {
-        todo!()
+    /// We closed the connection cleanly, the peer goes back to Idle.
+    pub async fn feedback_peer_closed(
+        &self,
+        addr: &SocketAddr,
+    ) -> Result<(), NetworkControllerError> {
+        self.feedback(addr, Feedback::Closed).await
+    }
+
+    async fn feedback(
+        &self,
+        addr: &SocketAddr,
+        feedback: Feedback,
+    ) -> Result<(), NetworkControllerError> {
+        let addr = *addr;
+        actor::request(&self.commands, |reply| Command::Feedback {
+            addr,
+            feedback,
+            reply,
+        })
+        .await?
     }
 
-    pub fn feedback_peer_banned(&self, ip: &IpAddr) {
-        todo!()
+    /// Merge the peer list sent by another peer into ours. Known peers keep their state, and new
+    /// peers only fill the free Idle slots so that gossip never evicts peers we already know.
+    pub async fn feedback_peer_list(
+        &self,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<(), NetworkControllerError> {
+        actor::request(&self.commands, |reply| Command::PeerList { addrs, reply }).await?
     }
 
-    pub fn feedback_peer_failed(&self, ip: &IpAddr) {
-        todo!()
+    /// Addresses of the known peers except banned ones and ourselves, sorted from best to worst.
+    pub async fn get_good_peer_ips(&self) -> Result<Vec<SocketAddr>, NetworkControllerError> {
+        actor::request(&self.commands, |reply| Command::GoodPeers { reply }).await
     }
+}
 
-    pub fn feedback_peer_closed(&self, ip: &IpAddr) {
-        todo!()
+pub struct NetworkControllerBuilder {
+    config: NetworkConfig,
+    transport: Arc<dyn Transport>,
+    clock: SharedClock,
+}
+
+impl NetworkControllerBuilder {
+    /// Listen and dial through `transport` instead of TCP.
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
     }
 
-    pub fn feedback_peer_list(&self) {
-        todo!()
+    /// Date the peer events with `clock` instead of the system clock.
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
     }
 
-    pub fn get_good_peer_ips(&self) {
-        todo!()
+    pub async fn start(self) -> Result<NetworkController, NetworkControllerError> {
+        NetworkController::start(self.config, self.transport, self.clock).await
     }
 }
 
+/// Reason why an incoming connection was closed right after being accepted
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionRejection {
+    /// the peer is banned
+    Banned,
+    /// a connection with the peer already exists
+    AlreadyConnected,
+    /// too many incoming connections
+    TooManyIncomingConnections,
+    /// too many incoming connections are handshaking
+    TooManyIncomingAttempts,
+    /// the connection comes from our own node
+    SelfConnection,
+}
+
+/// What `wait_event` returns: the handshaked connections, and every `NetworkEvent`
 pub enum NetworkControllerEvent {
+    /// A handshaked connection, handed over to the application
     CandidateConnection {
-        ip: String,
-        socket: TcpStream,
+        addr: SocketAddr,
+        socket: BoxedConnection,
         is_outgoing: bool,
     },
+    Event(NetworkEvent),
+}
+
+/// Background task of the controller
+#[derive(Display, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackgroundTask {
+    /// listener
+    Listener,
+    /// connector
+    Connector,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::clock::MockClock;
+    use crate::network::config::NetworkConfigBuilder;
+    use crate::network::event::EventStreamError;
+    use crate::network::file::PeersFileController;
+    use crate::network::peer::{Peer, PeerStatus};
+    use crate::network::test_utils::{addr, TempFile};
+    use crate::network::transport::MemoryNetwork;
+    use chrono::{DateTime, Utc};
+    use futures::StreamExt;
+    use std::collections::HashMap;
+    use std::ops::Deref;
+    use std::time::Duration;
+
+    /// Controller listening on `addr(i)` of a `MemoryNetwork`, whose peers file is removed when
+    /// it is dropped
+    struct Node {
+        net: NetworkController,
+        config: NetworkConfig,
+        _peers_file: TempFile,
+    }
+
+    impl Node {
+        /// Start the node knowing `peers`, with the settings of `configure`.
+        async fn start(
+            network: &MemoryNetwork,
+            i: u8,
+            peers: &[SocketAddr],
+            configure: impl FnOnce(NetworkConfigBuilder) -> NetworkConfigBuilder,
+        ) -> Self {
+            Self::start_with_clock(network, i, peers, configure, Arc::new(SystemClock)).await
+        }
+
+        async fn start_with_clock(
+            network: &MemoryNetwork,
+            i: u8,
+            peers: &[SocketAddr],
+            configure: impl FnOnce(NetworkConfigBuilder) -> NetworkConfigBuilder,
+            clock: SharedClock,
+        ) -> Self {
+            let peers_file = TempFile::new("peers.json");
+            std::fs::write(peers_file.path(), serde_json::to_string(peers).unwrap()).unwrap();
+            let builder = NetworkConfig::builder()
+                .peers_file(peers_file.to_str())
+                .listen_ip(addr(i).ip())
+                .listen_port(addr(i).port());
+            let config = configure(builder).build().unwrap();
+            let net = NetworkController::builder(config.clone())
+                .transport(Arc::new(network.transport(addr(i).ip())))
+                .clock(clock)
+                .start()
+                .await
+                .unwrap();
+
+            Node {
+                net,
+                config,
+                _peers_file: peers_file,
+            }
+        }
+
+        async fn shutdown(&self) -> Result<(), NetworkControllerError> {
+            self.net.clone().shutdown().await
+        }
+
+        fn read_peers(&self) -> HashMap<SocketAddr, Peer> {
+            PeersFileController::new(&self.config.peers_file, self.config.listen_port)
+                .read_file()
+                .unwrap()
+        }
+    }
+
+    impl Deref for Node {
+        type Target = NetworkController;
+
+        fn deref(&self) -> &NetworkController {
+            &self.net
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown() {
+        let network = MemoryNetwork::new();
+        let listening = Node::start(&network, 1, &[], |config| config).await;
+        let dialing = Node::start(&network, 2, &[addr(1)], |config| config).await;
+
+        let CandidateConnection { addr, socket, .. } = dialing.wait_event().await.unwrap() else {
+            panic!("Expected a connection");
+        };
+        dialing
+            .add_peer(addr.to_string(), Some(socket))
+            .await
+            .unwrap();
+        dialing.shutdown().await.unwrap();
+        listening.shutdown().await.unwrap();
+
+        // The connection was closed and the peers file flushed
+        let peers = dialing.read_peers();
+        assert_eq!(PeerStatus::Idle, peers[&addr].status());
+        assert!(peers[&addr].last_alive().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_transport() {
+        let network = MemoryNetwork::new();
+        let listening = Node::start(&network, 1, &[], |config| config).await;
+        let dialing = Node::start(&network, 2, &[addr(1)], |config| config).await;
+
+        for (net, peer, outgoing) in [(&dialing, addr(1), true), (&listening, addr(2), false)] {
+            let CandidateConnection {
+                addr,
+                socket,
+                is_outgoing,
+            } = net.wait_event().await.unwrap()
+            else {
+                panic!("Expected a connection");
+            };
+            assert_eq!((peer, outgoing), (addr, is_outgoing));
+            assert_eq!(peer.ip(), socket.peer_addr().unwrap().ip());
+            net.add_peer(addr.to_string(), Some(socket)).await.unwrap();
+            // Adding the connected peer again keeps its socket
+            net.add_peer(addr.to_string(), None).await.unwrap();
+        }
+
+        // Only connected peers can hold a socket
+        let socket = network.transport(addr(3).ip()).dial(addr(1)).await.unwrap();
+        dialing.add_peer(addr(4).to_string(), None).await.unwrap();
+        dialing.feedback_peer_banned(&addr(4)).await.unwrap();
+        assert!(matches!(
+            dialing.add_peer(addr(4).to_string(), Some(socket)).await,
+            Err(NetworkControllerError::NotConnected(banned)) if banned == addr(4)
+        ));
+
+        dialing.shutdown().await.unwrap();
+        listening.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_during_handshake() {
+        let network = MemoryNetwork::new();
+        let net = Node::start(&network, 1, &[], |config| {
+            config
+                .target_outgoing_connections(0)
+                .handshake_timeout_seconds(30)
+        })
+        .await;
+
+        // A connection that never handshakes is tracked under the port it comes from
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let _pending = network.transport(addr(2).ip()).dial(addr(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        net.shutdown().await.unwrap();
+
+        assert!(net.read_peers().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peer_events() {
+        let net = Node::start(&MemoryNetwork::new(), 1, &[], |config| {
+            config.target_outgoing_connections(0)
+        })
+        .await;
+        let bans = net
+            .subscribe(Some(vec![EventKind::PeerBanned]))
+            .await
+            .unwrap();
+
+        // Our own address is not learned
+        net.feedback_peer_list(vec![addr(2), addr(1), addr(3), addr(2)])
+            .await
+            .unwrap();
+        assert!(matches!(
+            net.wait_event().await.unwrap(),
+            NetworkControllerEvent::Event(NetworkEvent::PeersLearned { addrs })
+                if addrs == vec![addr(2), addr(3)]
+        ));
+        net.feedback_peer_banned(&addr(2)).await.unwrap();
+        assert!(matches!(
+            net.wait_event().await.unwrap(),
+            NetworkControllerEvent::Event(NetworkEvent::PeerBanned { addr: banned })
+                if banned == addr(2)
+        ));
+
+        // Nothing changed, nothing to report, and the banned host isn't learned on another port
+        net.feedback_peer_list(vec![addr(3), SocketAddr::new(addr(2).ip(), 9090)])
+            .await
+            .unwrap();
+        net.feedback_peer_banned(&addr(2)).await.unwrap();
+        assert!(net.feedback_peer_closed(&addr(3)).await.is_err());
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), net.wait_event())
+                .await
+                .is_err()
+        );
+
+        net.shutdown().await.unwrap();
+        // The subscriber only got the ban, and its stream ends with the controller
+        assert_eq!(
+            vec![Ok(NetworkEvent::PeerBanned { addr: addr(2) })],
+            bans.collect::<Vec<_>>().await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cloned_handles() {
+        let net = Node::start(&MemoryNetwork::new(), 1, &[], |config| {
+            config.target_outgoing_connections(0)
+        })
+        .await;
+
+        // Every task feeds the same peer table through its own handle
+        let tasks: Vec<_> = (2..6)
+            .map(|i| {
+                let net = net.clone();
+                tokio::spawn(async move { net.add_peer(addr(i).to_string(), None).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        let mut good = net.get_good_peer_ips().await.unwrap();
+        good.sort();
+        assert_eq!(vec![addr(2), addr(3), addr(4), addr(5)], good);
+
+        net.remove_peer(&addr(3)).await.unwrap();
+        assert!(matches!(
+            net.remove_peer(&addr(3)).await,
+            Err(NetworkControllerError::UnknownPeer(unknown)) if unknown == addr(3)
+        ));
+        assert!(!net.get_good_peer_ips().await.unwrap().contains(&addr(3)));
+
+        // The other handles are closed by the shutdown
+        net.shutdown().await.unwrap();
+        assert!(matches!(
+            net.get_good_peer_ips().await,
+            Err(NetworkControllerError::ClosedChanel)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock() {
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let net = Node::start_with_clock(
+            &MemoryNetwork::new(),
+            1,
+            &[],
+            |config| config.target_outgoing_connections(0),
+            Arc::new(MockClock::new(start)),
+        )
+        .await;
+
+        net.feedback_peer_list(vec![addr(2), addr(3)])
+            .await
+            .unwrap();
+        net.feedback_peer_banned(&addr(2)).await.unwrap();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        net.feedback_peer_banned(&addr(3)).await.unwrap();
+        net.shutdown().await.unwrap();
+
+        let peers = net.read_peers();
+        assert_eq!(Some(start), peers[&addr(2)].last_failure());
+        assert_eq!(
+            Some(start + chrono::Duration::seconds(60)),
+            peers[&addr(3)].last_failure()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_event_queue_overflow() {
+        let net = Node::start(&MemoryNetwork::new(), 1, &[], |config| {
+            config
+                .target_outgoing_connections(0)
+                .event_queue_capacity(1)
+        })
+        .await;
+        let mut subscriber = net.subscribe(None).await.unwrap();
+
+        net.feedback_peer_list(vec![addr(2)]).await.unwrap();
+        net.feedback_peer_banned(&addr(2)).await.unwrap();
+        assert_eq!(1, net.dropped_count());
+        assert!(matches!(
+            net.wait_event().await.unwrap(),
+            NetworkControllerEvent::Event(NetworkEvent::PeersLearned { .. })
+        ));
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), net.wait_event())
+                .await
+                .is_err()
+        );
+        // The subscribers have their own queue, of the same capacity
+        assert_eq!(
+            Err(EventStreamError::Lagged(1)),
+            subscriber.next().await.unwrap()
+        );
+        assert_eq!(
+            Ok(NetworkEvent::PeerBanned { addr: addr(2) }),
+            subscriber.next().await.unwrap()
+        );
+
+        net.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_listener_failure() {
+        // The listen address is already taken
+        let network = MemoryNetwork::new();
+        let _taken = network
+            .transport(addr(1).ip())
+            .listen(addr(1))
+            .await
+            .unwrap();
+        let net = Node::start(&network, 1, &[], |config| config.task_restart_attempts(1)).await;
+
+        for expected_restarting in [true, false] {
+            let event = net.wait_event().await.unwrap();
+            assert!(matches!(
+                event,
+                NetworkControllerEvent::Event(NetworkEvent::TaskFailed {
+                    task: BackgroundTask::Listener,
+                    restarting,
+                    ..
+                }) if restarting == expected_restarting
+            ));
+        }
+        assert!(matches!(
+            net.wait_event().await,
+            Err(NetworkControllerError::TaskStopped {
+                task: BackgroundTask::Listener,
+                ..
+            })
+        ));
+        assert!(matches!(
+            net.shutdown().await,
+            Err(NetworkControllerError::TasksFailed(errors)) if errors.len() == 1
+        ));
+    }
 }
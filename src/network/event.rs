@@ -0,0 +1,277 @@
+use crate::network::controller::{BackgroundTask, ConnectionRejection, NetworkControllerError};
+use crate::network::queue::{QueueError, QueueSender};
+use crate::network::sync;
+use crate::network::transport::BoxedConnection;
+use displaydoc::Display;
+use futures::Stream;
+use log::{debug, warn};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+
+#[derive(Display, Error, Debug, Clone, PartialEq, Eq)]
+pub enum EventStreamError {
+    /// The subscriber is too slow, {0} events were dropped
+    Lagged(u64),
+}
+
+/// What happens to the peers and the background tasks of the controller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkEvent {
+    /// The peer went to InAlive or OutAlive status
+    PeerAlive {
+        addr: SocketAddr,
+        is_outgoing: bool,
+    },
+    /// The connection with the peer was closed cleanly
+    PeerClosed {
+        addr: SocketAddr,
+    },
+    /// Connecting or handshaking with the peer failed, or its connection broke
+    PeerFailed {
+        addr: SocketAddr,
+    },
+    PeerBanned {
+        addr: SocketAddr,
+    },
+    /// Peers dropped from the peer table to respect the Idle and Banned limits
+    PeersEvicted {
+        addrs: Vec<SocketAddr>,
+    },
+    /// New peers added from the peer list of another peer
+    PeersLearned {
+        addrs: Vec<SocketAddr>,
+    },
+    /// An incoming connection was closed right after being accepted or handshaked
+    ConnectionRejected {
+        addr: SocketAddr,
+        reason: ConnectionRejection,
+    },
+    /// A background task failed, and is restarted after a delay unless `restarting` is false
+    TaskFailed {
+        task: BackgroundTask,
+        error: String,
+        restarting: bool,
+    },
+}
+
+/// What the controller queues for `wait_event`
+pub enum QueueItem {
+    /// A handshaked connection
+    Connection {
+        addr: SocketAddr,
+        socket: BoxedConnection,
+        is_outgoing: bool,
+    },
+    Event(NetworkEvent),
+}
+
+/// Kind of a `NetworkEvent`, to subscribe to some events only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    PeerAlive,
+    PeerClosed,
+    PeerFailed,
+    PeerBanned,
+    PeersEvicted,
+    PeersLearned,
+    ConnectionRejected,
+    TaskFailed,
+}
+
+impl NetworkEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            NetworkEvent::PeerAlive { .. } => EventKind::PeerAlive,
+            NetworkEvent::PeerClosed { .. } => EventKind::PeerClosed,
+            NetworkEvent::PeerFailed { .. } => EventKind::PeerFailed,
+            NetworkEvent::PeerBanned { .. } => EventKind::PeerBanned,
+            NetworkEvent::PeersEvicted { .. } => EventKind::PeersEvicted,
+            NetworkEvent::PeersLearned { .. } => EventKind::PeersLearned,
+            NetworkEvent::ConnectionRejected { .. } => EventKind::ConnectionRejected,
+            NetworkEvent::TaskFailed { .. } => EventKind::TaskFailed,
+        }
+    }
+}
+
+/// Sends the connections and the events to `wait_event`, and the events to the subscribers too.
+#[derive(Clone)]
+pub struct EventSender {
+    queue: QueueSender<QueueItem>,
+    subscribers: broadcast::Sender<NetworkEvent>,
+    /// Connections dropped from the full queue, whose peers must be set back to Idle
+    dropped_connections: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl EventSender {
+    /// `capacity` is the number of events kept for the slowest subscriber before it lags.
+    pub fn new(queue: QueueSender<QueueItem>, capacity: usize) -> Self {
+        let (subscribers, _) = broadcast::channel(capacity);
+        EventSender {
+            queue,
+            subscribers,
+            dropped_connections: Arc::default(),
+        }
+    }
+
+    /// Hand a handshaked connection over to `wait_event`.
+    pub fn connection(
+        &self,
+        addr: SocketAddr,
+        socket: BoxedConnection,
+        is_outgoing: bool,
+    ) -> Result<(), NetworkControllerError> {
+        let item = QueueItem::Connection {
+            addr,
+            socket,
+            is_outgoing,
+        };
+        match self.queue.push(item) {
+            Ok(dropped) => {
+                self.dropped(dropped);
+                Ok(())
+            }
+            Err(QueueError::Full) => Err(NetworkControllerError::QueueFull { peer_addr: addr }),
+            Err(QueueError::Closed) => {
+                Err(NetworkControllerError::ChannelError { peer_addr: addr })
+            }
+        }
+    }
+
+    pub fn notify(&self, event: NetworkEvent) {
+        // Having no subscriber is fine
+        let _ = self.subscribers.send(event.clone());
+        match self.queue.push(QueueItem::Event(event)) {
+            Ok(dropped) => self.dropped(dropped),
+            Err(QueueError::Full) => debug!("Event dropped, the queue is full"),
+            Err(QueueError::Closed) => warn!("Can't emit an event, the queue is closed"),
+        }
+    }
+
+    /// Emit an event `wait_event` must get even if the queue is full.
+    pub fn notify_always(&self, event: NetworkEvent) {
+        let _ = self.subscribers.send(event.clone());
+        if self.queue.force_push(QueueItem::Event(event)).is_err() {
+            warn!("Can't emit an event, the queue is closed");
+        }
+    }
+
+    fn dropped(&self, item: Option<QueueItem>) {
+        match item {
+            Some(QueueItem::Connection { addr, .. }) => {
+                debug!("Connection to {} dropped, the queue is full", addr);
+                sync::lock(&self.dropped_connections).push(addr);
+            }
+            Some(QueueItem::Event(_)) => debug!("Event dropped, the queue is full"),
+            None => {}
+        }
+    }
+
+    /// Addresses of the connections dropped from the queue since the last call.
+    pub fn take_dropped_connections(&self) -> Vec<SocketAddr> {
+        std::mem::take(&mut *sync::lock(&self.dropped_connections))
+    }
+
+    /// Wait for room in the queue, see `QueueSender::ready`.
+    pub async fn ready(&self) {
+        self.queue.ready().await
+    }
+
+    /// Connections and events dropped or rejected because the queue was full
+    pub fn dropped_count(&self) -> u64 {
+        self.queue.dropped()
+    }
+
+    /// Stream of the events sent from now on, of the given kinds or of all kinds.
+    pub fn subscribe(&self, kinds: Option<Vec<EventKind>>) -> EventStream {
+        EventStream {
+            inner: BroadcastStream::new(self.subscribers.subscribe()),
+            kinds,
+        }
+    }
+}
+
+/// Events of the controller, ending once it is shut down. A subscriber that doesn't keep up gets
+/// a `Lagged` error, then the events that are still buffered.
+pub struct EventStream {
+    inner: BroadcastStream<NetworkEvent>,
+    kinds: Option<Vec<EventKind>>,
+}
+
+impl Stream for EventStream {
+    type Item = Result<NetworkEvent, EventStreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let event = match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) => event,
+                Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(skipped)))) => {
+                    return Poll::Ready(Some(Err(EventStreamError::Lagged(skipped))))
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let wanted = self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&event.kind()));
+            if wanted {
+                return Poll::Ready(Some(Ok(event)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::queue::{self, OverflowPolicy};
+    use crate::network::test_utils::addr;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let (queue, receiver) = queue::bounded(16, OverflowPolicy::Reject);
+        let events = EventSender::new(queue, 2);
+        let mut all = events.subscribe(None);
+        let mut banned = events.subscribe(Some(vec![EventKind::PeerBanned]));
+
+        events.notify(NetworkEvent::PeerFailed { addr: addr(1) });
+        events.notify(NetworkEvent::PeerBanned { addr: addr(2) });
+        assert_eq!(
+            Some(Ok(NetworkEvent::PeerFailed { addr: addr(1) })),
+            all.next().await
+        );
+        assert_eq!(
+            Some(Ok(NetworkEvent::PeerBanned { addr: addr(2) })),
+            banned.next().await
+        );
+
+        // The slow subscriber misses the oldest events, `wait_event` gets them all
+        for i in 3..6 {
+            events.notify(NetworkEvent::PeerBanned { addr: addr(i) });
+        }
+        assert_eq!(Some(Err(EventStreamError::Lagged(2))), all.next().await);
+        assert_eq!(
+            Some(Ok(NetworkEvent::PeerBanned { addr: addr(4) })),
+            all.next().await
+        );
+        let mut received = 0;
+        while receiver.try_recv().is_some() {
+            received += 1;
+        }
+        assert_eq!(5, received);
+
+        drop(events);
+        assert_eq!(
+            Some(Ok(NetworkEvent::PeerBanned { addr: addr(5) })),
+            all.next().await
+        );
+        assert_eq!(None, all.next().await);
+    }
+}
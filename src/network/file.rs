@@ -1,14 +1,17 @@
 use crate::error_logger::InspectErr;
-use crate::network::peer::{Peer, PeerError};
+use crate::network::clock::{SharedClock, SystemClock};
+use crate::network::peer::{self, Peer, PeerError, PeerStatus};
+use chrono::{DateTime, Utc};
 use displaydoc::Display;
 use log::{info, warn};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::{AddrParseError, IpAddr};
-use std::str::FromStr;
+use std::io::Write;
+use std::net::{AddrParseError, SocketAddr};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{fs, io};
 use thiserror::Error;
-use tokio::sync::RwLock;
 
 #[derive(Display, Error, Debug)]
 pub enum PeersFileControllerError {
@@ -18,93 +21,359 @@ pub enum PeersFileControllerError {
     Serialization(#[from] serde_json::Error),
     /// Can't parse IP Address: {0}
     IpAddressFormat(#[from] AddrParseError),
-    /// Impossible to acquire lock over a RwLock
-    RwLockPoisoned,
     /// Error with a peer: {0}
     Peer(#[from] PeerError),
+    /// Unsupported peers file version {0}
+    UnsupportedVersion(u32),
+}
+
+/// Version of the peers file written by `write_file`
+const PEERS_FILE_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeersFileFormat {
+    /// First format of the file: a plain list of IPs
+    Legacy(Vec<String>),
+    Versioned(PeersFile),
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeersFile {
+    version: u32,
+    peers: Vec<PeerRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerRecord {
+    /// `ip:port`
+    addr: String,
+    status: PersistedStatus,
+    last_alive: Option<DateTime<Utc>>,
+    last_failure: Option<DateTime<Utc>>,
+    /// Failures in a row, and the reconnect backoff they lead to
+    #[serde(default, skip_serializing_if = "is_zero")]
+    failures: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_dial: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    is_self: bool,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+/// Connections don't survive a restart, so only bans are persisted
+#[derive(Serialize, Deserialize, Clone, Copy)]
+enum PersistedStatus {
+    Idle,
+    Banned,
+}
+
+impl From<PeerStatus> for PersistedStatus {
+    fn from(status: PeerStatus) -> Self {
+        match status {
+            PeerStatus::Banned => PersistedStatus::Banned,
+            _ => PersistedStatus::Idle,
+        }
+    }
+}
+
+impl From<PersistedStatus> for PeerStatus {
+    fn from(status: PersistedStatus) -> Self {
+        match status {
+            PersistedStatus::Idle => PeerStatus::Idle,
+            PersistedStatus::Banned => PeerStatus::Banned,
+        }
+    }
+}
+
+impl From<&Peer> for PeerRecord {
+    fn from(peer: &Peer) -> Self {
+        PeerRecord {
+            addr: peer.addr().to_string(),
+            status: peer.status().into(),
+            last_alive: peer.last_alive(),
+            last_failure: peer.last_failure(),
+            failures: peer.failures(),
+            next_dial: peer.next_dial(),
+            is_self: peer.is_self(),
+        }
+    }
 }
 
-#[derive(Default)]
 pub struct PeersFileController {
     file_path: String,
+    /// Port of the peers saved without one
+    default_port: u16,
     is_changed: AtomicBool,
+    clock: SharedClock,
 }
 
 impl PeersFileController {
-    pub fn new(file: &str) -> PeersFileController {
+    pub fn new(file: &str, default_port: u16) -> PeersFileController {
         PeersFileController {
             file_path: file.to_string(),
+            default_port,
             is_changed: AtomicBool::new(false),
+            clock: Arc::new(SystemClock),
         }
     }
 
+    /// Use `clock` instead of the system clock to read the file.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub fn changed(&self) {
         self.is_changed.store(true, Ordering::SeqCst);
     }
 
-    fn parse_peer(data: String) -> Result<HashMap<IpAddr, Peer>, PeersFileControllerError> {
-        let data: Vec<String> = serde_json::from_str(&data)?;
+    fn parse_peer(
+        data: String,
+        default_port: u16,
+        now: DateTime<Utc>,
+    ) -> Result<HashMap<SocketAddr, Peer>, PeersFileControllerError> {
+        let data = match serde_json::from_str(&data)? {
+            PeersFileFormat::Legacy(ips) => ips,
+            PeersFileFormat::Versioned(file) => return Self::restore_peers(file, now),
+        };
 
         Ok(data
             .iter()
-            .map(|ip| -> Result<(IpAddr, Peer), PeersFileControllerError> {
-                Ok((
-                    IpAddr::from_str(ip).inspect_error(|err| warn!("Can't parse ip {}", err))?,
-                    Peer::new(ip)?,
-                ))
-            })
-            .flatten()
+            .flat_map(
+                |ip| -> Result<(SocketAddr, Peer), PeersFileControllerError> {
+                    let addr = peer::parse_addr(ip, default_port)
+                        .inspect_error(|err| warn!("Can't parse ip {}", err))?;
+                    Ok((addr, Peer::from_addr(addr)))
+                },
+            )
+            .collect())
+    }
+
+    /// Dates after `now`, written by a node whose clock was ahead, are brought back to `now` so
+    /// that they don't look recent forever. Next dial dates are kept within the longest backoff.
+    /// Records with an invalid address are skipped, like in the legacy format.
+    fn restore_peers(
+        file: PeersFile,
+        now: DateTime<Utc>,
+    ) -> Result<HashMap<SocketAddr, Peer>, PeersFileControllerError> {
+        if file.version != PEERS_FILE_VERSION {
+            return Err(PeersFileControllerError::UnsupportedVersion(file.version));
+        }
+
+        let max_next_dial = now + chrono::Duration::seconds(peer::MAX_RECONNECT_DELAY_SECONDS);
+        Ok(file
+            .peers
+            .into_iter()
+            .flat_map(
+                |record| -> Result<(SocketAddr, Peer), PeersFileControllerError> {
+                    let addr: SocketAddr = record
+                        .addr
+                        .parse()
+                        .inspect_error(|err| warn!("Can't parse ip {}", err))?;
+                    let mut peer = Peer::restore(
+                        addr,
+                        record.status.into(),
+                        record.last_alive.map(|date| date.min(now)),
+                        record.last_failure.map(|date| date.min(now)),
+                        record.failures,
+                        record.next_dial.map(|date| date.min(max_next_dial)),
+                    );
+                    if record.is_self {
+                        peer.mark_self();
+                    }
+                    Ok((addr, peer))
+                },
+            )
             .collect())
     }
 
-    pub fn read_file(&self) -> Result<HashMap<IpAddr, Peer>, PeersFileControllerError> {
+    fn serialize_peers(
+        peers: &HashMap<SocketAddr, Peer>,
+    ) -> Result<String, PeersFileControllerError> {
+        let file = PeersFile {
+            version: PEERS_FILE_VERSION,
+            // Incoming connections handshake under the address they come from, which isn't the
+            // one the peer listens on
+            peers: peers
+                .values()
+                .filter(|peer| peer.status() != PeerStatus::InHandshaking)
+                .map(PeerRecord::from)
+                .collect(),
+        };
+
+        Ok(serde_json::to_string(&file)?)
+    }
+
+    pub fn read_file(&self) -> Result<HashMap<SocketAddr, Peer>, PeersFileControllerError> {
         let json = fs::read_to_string(&self.file_path)?;
 
-        Self::parse_peer(json)
+        Self::parse_peer(json, self.default_port, self.clock.now())
     }
 
     pub async fn write_file(
         &self,
-        peers: &RwLock<HashMap<IpAddr, Peer>>,
+        peers: &HashMap<SocketAddr, Peer>,
     ) -> Result<(), PeersFileControllerError> {
-        if !self.is_changed.load(Ordering::Relaxed) {
+        // Changes made while writing will be dumped next time
+        if !self.is_changed.swap(false, Ordering::SeqCst) {
             return Ok(());
         };
 
-        let ips: Vec<String> = peers
-            .read()
+        let json = Self::serialize_peers(peers)?;
+        let file_path = self.file_path.clone();
+        tokio::task::spawn_blocking(move || Self::write_atomically(&file_path, &json))
             .await
-            .iter()
-            .map(|peer| peer.0.to_string())
-            .collect();
-        let json = serde_json::to_string(&ips)?;
-
-        fs::write(&self.file_path, &json)?;
-        self.is_changed.store(false, Ordering::SeqCst);
+            .map_err(io::Error::other)
+            .and_then(|result| result)
+            .inspect_error(|_| self.changed())?;
 
         info!("Json peers list dumped");
         Ok(())
     }
+
+    /// Write in a temporary file renamed over the peers file, so that a crash never leaves a
+    /// truncated peers file.
+    fn write_atomically(file_path: &str, json: &str) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", file_path);
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(&tmp_path, file_path)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::IpAddr;
+    use crate::network::clock::MockClock;
+    use crate::network::test_utils::TempFile;
 
     #[test]
     fn test_read_file() {
         let input = "[\"192.168.1.1\", \"192.168.2.1\", \"192.168.3.1\"]".to_string();
 
-        let peers: Vec<String> = PeersFileController::parse_peer(input)
+        let peers: Vec<String> = PeersFileController::parse_peer(input, 8080, Utc::now())
             .expect("A list of peers")
             .keys()
-            .map(IpAddr::to_string)
+            .map(SocketAddr::to_string)
             .collect();
 
         assert_eq!(3, peers.len());
-        assert!(peers.contains(&String::from("192.168.1.1")));
-        assert!(peers.contains(&String::from("192.168.2.1")));
-        assert!(peers.contains(&String::from("192.168.3.1")));
+        assert!(peers.contains(&String::from("192.168.1.1:8080")));
+        assert!(peers.contains(&String::from("192.168.2.1:8080")));
+        assert!(peers.contains(&String::from("192.168.3.1:8080")));
+    }
+
+    #[test]
+    fn test_peers_file_round_trip() {
+        let mut peers = PeersFileController::parse_peer(
+            "[\"192.168.1.1\", \"192.168.2.1:8081\", \"192.168.3.1\"]".to_string(),
+            8080,
+            Utc::now(),
+        )
+        .expect("A list of peers");
+        let banned: SocketAddr = "192.168.1.1:8080".parse().unwrap();
+        let alive: SocketAddr = "192.168.2.1:8081".parse().unwrap();
+        peers.get_mut(&banned).unwrap().banned(Utc::now());
+        let myself: SocketAddr = "192.168.3.1:8080".parse().unwrap();
+        peers.get_mut(&myself).unwrap().mark_self();
+        let alive_peer = peers.get_mut(&alive).unwrap();
+        alive_peer.handshake(true);
+        alive_peer.alive(Utc::now()).unwrap();
+
+        let handshaking: SocketAddr = "192.168.4.1:50000".parse().unwrap();
+        let mut incoming = Peer::from_addr(handshaking);
+        incoming.handshake(false);
+        peers.insert(handshaking, incoming);
+
+        let unreachable: SocketAddr = "192.168.5.1:8080".parse().unwrap();
+        let mut failing = Peer::from_addr(unreachable);
+        failing.connecting();
+        failing.failed(Utc::now(), 0).unwrap();
+        peers.insert(unreachable, failing);
+
+        let json = PeersFileController::serialize_peers(&peers).expect("A json");
+        // The default port only applies to the legacy list of IPs
+        let restored =
+            PeersFileController::parse_peer(json, 9090, Utc::now()).expect("A list of peers");
+
+        assert_eq!(4, restored.len());
+        assert_eq!(PeerStatus::Banned, restored[&banned].status());
+        assert_eq!(
+            peers[&banned].last_failure(),
+            restored[&banned].last_failure()
+        );
+        // Connections don't survive a restart
+        assert_eq!(PeerStatus::Idle, restored[&alive].status());
+        assert_eq!(peers[&alive].last_alive(), restored[&alive].last_alive());
+        assert!(restored[&myself].is_self());
+        assert!(!restored[&alive].is_self());
+        assert_eq!(1, restored[&unreachable].failures());
+        assert_eq!(
+            peers[&unreachable].next_dial(),
+            restored[&unreachable].next_dial()
+        );
+        assert_eq!(None, restored[&alive].next_dial());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let input = "{\"version\": 42, \"peers\": []}".to_string();
+
+        assert!(matches!(
+            PeersFileController::parse_peer(input, 8080, Utc::now()),
+            Err(PeersFileControllerError::UnsupportedVersion(42))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_record() {
+        let input = "{\"version\": 1, \"peers\": [{\"addr\": \"not an ip\", \"status\": \"Idle\", \"last_alive\": null, \"last_failure\": null}, {\"addr\": \"192.168.1.1:8080\", \"status\": \"Idle\", \"last_alive\": null, \"last_failure\": null}]}".to_string();
+
+        // The bad record is skipped, the others are kept
+        let peers =
+            PeersFileController::parse_peer(input, 8080, Utc::now()).expect("A list of peers");
+        assert_eq!(1, peers.len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dates_from_the_future() {
+        let file = TempFile::new("peers.json");
+        let input = "{\"version\": 1, \"peers\": [{\"addr\": \"192.168.1.1:8080\", \"status\": \"Banned\", \"last_alive\": \"2023-06-01T00:00:00Z\", \"last_failure\": \"2030-01-01T00:00:00Z\"}]}";
+        fs::write(file.path(), input).unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let file_controller =
+            PeersFileController::new(file.to_str(), 8080).with_clock(Arc::new(MockClock::new(now)));
+
+        let peers = file_controller.read_file().expect("A list of peers");
+        let peer = &peers[&"192.168.1.1:8080".parse().unwrap()];
+        assert_eq!(Some(now), peer.last_failure());
+        assert!(peer.last_alive() < Some(now));
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let file = TempFile::new("peers.json");
+        let file_controller = PeersFileController::new(file.to_str(), 8080);
+        let peers =
+            PeersFileController::parse_peer("[\"192.168.1.1\"]".to_string(), 8080, Utc::now())
+                .expect("A list of peers");
+
+        // Nothing changed, nothing written
+        file_controller.write_file(&peers).await.unwrap();
+        assert!(!file.path().exists());
+
+        file_controller.changed();
+        file_controller.write_file(&peers).await.unwrap();
+        let restored = file_controller.read_file().expect("A list of peers");
+        assert!(restored.contains_key(&"192.168.1.1:8080".parse().unwrap()));
+        assert!(!file.path().with_extension("json.tmp").exists());
     }
 }
@@ -0,0 +1,147 @@
+use crate::network::codec::{self, CodecError};
+use crate::network::message::ChannelMessage;
+use crate::network::random::random_u64;
+use displaydoc::Display;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Version of the protocol spoken by this node, peers must speak the same version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Maximum size of a handshake frame, in bytes
+const MAX_HANDSHAKE_FRAME_SIZE: usize = 1024;
+
+#[derive(Display, Error, Debug)]
+pub enum HandshakeError {
+    /// Codec error: {0}
+    Codec(#[from] CodecError),
+    /// The handshake timed out
+    Timeout,
+    /// Expected a handshake, got {0}
+    UnexpectedMessage(String),
+    /// Incompatible protocol version {0}
+    IncompatibleVersion(u32),
+    /// The peer is on network {0}
+    DifferentNetwork(String),
+    /// The peer is our own node
+    SelfConnection,
+}
+
+/// What a node tells about itself when handshaking
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeInfo {
+    pub version: u32,
+    pub network_id: String,
+    /// Port on which the node accepts incoming connections
+    pub listen_port: u16,
+    /// Random number identifying the node process
+    pub nonce: u64,
+}
+
+/// Performs the handshake on new connections, on behalf of our node
+pub struct Handshaker {
+    local: HandshakeInfo,
+    timeout: Duration,
+}
+
+impl Handshaker {
+    pub fn new(network_id: &str, listen_port: u16, timeout: Duration) -> Self {
+        Handshaker {
+            local: HandshakeInfo {
+                version: PROTOCOL_VERSION,
+                network_id: network_id.to_string(),
+                listen_port,
+                nonce: random_u64(),
+            },
+            timeout,
+        }
+    }
+
+    pub fn local(&self) -> &HandshakeInfo {
+        &self.local
+    }
+
+    /// Send our handshake, then read and check the one of the peer.
+    pub async fn run<S>(&self, socket: &mut S) -> Result<HandshakeInfo, HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        tokio::time::timeout(self.timeout, self.exchange(socket))
+            .await
+            .map_err(|_| HandshakeError::Timeout)?
+    }
+
+    async fn exchange<S>(&self, socket: &mut S) -> Result<HandshakeInfo, HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        codec::write_message(socket, &ChannelMessage::Handshake(self.local.clone())).await?;
+
+        let remote = match codec::read_message(socket, MAX_HANDSHAKE_FRAME_SIZE).await? {
+            ChannelMessage::Handshake(remote) => remote,
+            message => return Err(HandshakeError::UnexpectedMessage(format!("{:?}", message))),
+        };
+        if remote.nonce == self.local.nonce {
+            return Err(HandshakeError::SelfConnection);
+        }
+        if remote.version != self.local.version {
+            return Err(HandshakeError::IncompatibleVersion(remote.version));
+        }
+        if remote.network_id != self.local.network_id {
+            return Err(HandshakeError::DifferentNetwork(remote.network_id));
+        }
+
+        Ok(remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let ours = Handshaker::new("test", 8080, Duration::from_secs(1));
+        let theirs = Handshaker::new("test", 8081, Duration::from_secs(1));
+
+        let (remote, local) = tokio::join!(ours.run(&mut client), theirs.run(&mut server));
+
+        assert_eq!(theirs.local(), &remote.expect("Their handshake"));
+        assert_eq!(ours.local(), &local.expect("Our handshake"));
+    }
+
+    #[tokio::test]
+    async fn test_different_network() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let ours = Handshaker::new("test", 8080, Duration::from_secs(1));
+        let theirs = Handshaker::new("other", 8080, Duration::from_secs(1));
+
+        let (remote, _) = tokio::join!(ours.run(&mut client), theirs.run(&mut server));
+
+        assert!(matches!(remote, Err(HandshakeError::DifferentNetwork(id)) if id == "other"));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let (mut client, _server) = tokio::io::duplex(1024);
+        let ours = Handshaker::new("test", 8080, Duration::from_millis(10));
+
+        assert!(matches!(
+            ours.run(&mut client).await,
+            Err(HandshakeError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_self_connection() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let ours = Handshaker::new("test", 8080, Duration::from_secs(1));
+
+        let (remote, local) = tokio::join!(ours.run(&mut client), ours.run(&mut server));
+
+        assert!(matches!(remote, Err(HandshakeError::SelfConnection)));
+        assert!(matches!(local, Err(HandshakeError::SelfConnection)));
+    }
+}
@@ -1,17 +1,12 @@
-use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
-use tokio::net::TcpStream;
+use crate::network::handshake::HandshakeInfo;
+use std::net::SocketAddr;
 
+/// Messages exchanged with the peers, see `codec` for their encoding
 #[derive(Debug)]
 pub enum ChannelMessage {
-    Connection {
-        ip: IpAddr,
-        socket: TcpStream,
-        is_outgoing: bool,
-    },
-    Handshake,
+    Handshake(HandshakeInfo),
     Alive,
     AskPeersList,
-    PeersList(String),
+    PeersList(Vec<SocketAddr>),
     Close,
 }
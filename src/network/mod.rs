@@ -1,4 +1,16 @@
+mod actor;
+pub mod clock;
+pub mod codec;
+pub mod config;
 pub mod controller;
-mod file;
-mod message;
+pub mod event;
+pub mod file;
+pub mod handshake;
+pub mod message;
 pub mod peer;
+pub mod queue;
+mod random;
+mod sync;
+#[cfg(test)]
+mod test_utils;
+pub mod transport;
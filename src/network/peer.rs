@@ -1,36 +1,120 @@
+use crate::network::transport::BoxedConnection;
 use chrono::{DateTime, Utc};
 use displaydoc::Display;
-use std::net::{AddrParseError, IpAddr};
+use std::net::{AddrParseError, IpAddr, SocketAddr};
 use thiserror::Error;
-use tokio::net::TcpStream;
 
 #[derive(Display, Error, Debug)]
 pub enum PeerError {
     /// Can't parse IP Address: {0}
     IpAddressFormat(#[from] AddrParseError),
+    /// Peer {addr} can't go from {from:?} to {to:?}
+    InvalidTransition {
+        addr: SocketAddr,
+        from: PeerStatus,
+        to: PeerStatus,
+    },
 }
 
+const SCORE_UNIT: i64 = 1000;
+const SCORE_HALF_LIFE_SECONDS: i64 = 3600;
+/// Delay before dialing a peer again after its first failure, doubled at each failure
+const RECONNECT_DELAY_SECONDS: i64 = 5;
+/// Longest delay between two dials of a failing peer
+pub(crate) const MAX_RECONNECT_DELAY_SECONDS: i64 = 3600;
+
 pub struct Peer {
-    ip: IpAddr,
+    addr: SocketAddr,
     status: PeerStatus,
-    pub socket: Option<TcpStream>,
+    pub socket: Option<BoxedConnection>,
     last_alive: Option<DateTime<Utc>>,
     last_failure: Option<DateTime<Utc>>,
+    /// Failures since the peer was last alive
+    failures: u32,
+    /// The peer isn't dialed before this date, after a failure
+    next_dial: Option<DateTime<Utc>>,
+    superseded_outgoing: bool,
+    is_self: bool,
 }
 
 impl Peer {
-    pub fn new(ip: &String) -> Result<Self, PeerError> {
-        Ok(Peer {
-            ip: ip.parse::<IpAddr>()?,
+    pub fn new(addr: &str) -> Result<Self, PeerError> {
+        Ok(Self::from_addr(addr.parse::<SocketAddr>()?))
+    }
+
+    pub fn from_addr(addr: SocketAddr) -> Self {
+        Peer {
+            addr,
             status: PeerStatus::Idle,
             socket: None,
             last_alive: None,
             last_failure: None,
-        })
+            failures: 0,
+            next_dial: None,
+            superseded_outgoing: false,
+            is_self: false,
+        }
+    }
+
+    /// Peer loaded from the peers file, with its previous state.
+    pub fn restore(
+        addr: SocketAddr,
+        status: PeerStatus,
+        last_alive: Option<DateTime<Utc>>,
+        last_failure: Option<DateTime<Utc>>,
+        failures: u32,
+        next_dial: Option<DateTime<Utc>>,
+    ) -> Self {
+        Peer {
+            status,
+            last_alive,
+            last_failure,
+            failures,
+            next_dial,
+            ..Self::from_addr(addr)
+        }
+    }
+
+    /// Address on which the peer accepts connections
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn status(&self) -> PeerStatus {
+        self.status
+    }
+
+    pub fn last_alive(&self) -> Option<DateTime<Utc>> {
+        self.last_alive
+    }
+
+    pub fn last_failure(&self) -> Option<DateTime<Utc>> {
+        self.last_failure
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn next_dial(&self) -> Option<DateTime<Utc>> {
+        self.next_dial
+    }
+
+    /// The reconnect backoff of the peer is over.
+    pub fn can_dial(&self, now: DateTime<Utc>) -> bool {
+        self.next_dial.is_none_or(|next_dial| next_dial <= now)
+    }
+
+    /// The peer is our own node, we must never connect to it.
+    pub fn is_self(&self) -> bool {
+        self.is_self
     }
 
-    pub fn ip(&self) -> &IpAddr {
-        &self.ip
+    pub fn mark_self(&mut self) {
+        self.is_self = true;
+        self.status = PeerStatus::Idle;
+        self.socket = None;
+        self.superseded_outgoing = false;
     }
 
     pub fn connecting(&mut self) {
@@ -47,8 +131,131 @@ impl Peer {
             false => self.status = PeerStatus::InHandshaking,
         }
     }
+
+    /// An incoming connection won a simultaneous open against our outgoing connection, which
+    /// the remote peer is going to close.
+    pub fn supersede_outgoing(&mut self) {
+        self.superseded_outgoing = matches!(
+            self.status,
+            PeerStatus::OutHandshaking | PeerStatus::OutAlive
+        );
+        self.status = PeerStatus::InHandshaking;
+    }
+
+    /// The handshake succeeded or an alive peer gave a sign of life.
+    pub fn alive(&mut self, now: DateTime<Utc>) -> Result<(), PeerError> {
+        self.status = match self.status {
+            PeerStatus::InHandshaking | PeerStatus::InAlive => PeerStatus::InAlive,
+            PeerStatus::OutHandshaking | PeerStatus::OutAlive => PeerStatus::OutAlive,
+            from @ PeerStatus::OutConnecting => {
+                return Err(self.invalid_transition(from, PeerStatus::OutAlive))
+            }
+            from => return Err(self.invalid_transition(from, PeerStatus::InAlive)),
+        };
+        self.last_alive = Some(now);
+        self.failures = 0;
+        self.next_dial = None;
+
+        Ok(())
+    }
+
+    /// The peer misbehaved, `last_failure` holds the time of the ban.
+    pub fn banned(&mut self, now: DateTime<Utc>) {
+        self.status = PeerStatus::Banned;
+        self.superseded_outgoing = false;
+        self.socket = None;
+        self.last_failure = Some(now);
+    }
+
+    /// A connection or handshake with the peer failed. It isn't dialed again before a backoff
+    /// delay, shortened by up to half by `jitter`, a random number.
+    pub fn failed(&mut self, now: DateTime<Utc>, jitter: u64) -> Result<(), PeerError> {
+        if !self.status.is_connected() && self.status != PeerStatus::OutConnecting {
+            return Err(self.invalid_transition(self.status, PeerStatus::Idle));
+        }
+        if self.consume_superseded_outgoing() {
+            return Ok(());
+        }
+        self.status = PeerStatus::Idle;
+        self.socket = None;
+        self.last_failure = Some(now);
+        self.failures = self.failures.saturating_add(1);
+        self.next_dial = Some(now + Self::reconnect_delay(self.failures, jitter));
+
+        Ok(())
+    }
+
+    /// We closed the connection with the peer cleanly.
+    pub fn closed(&mut self, now: DateTime<Utc>) -> Result<(), PeerError> {
+        if !self.status.is_connected() {
+            return Err(self.invalid_transition(self.status, PeerStatus::Idle));
+        }
+        if self.consume_superseded_outgoing() {
+            return Ok(());
+        }
+        if self.status.is_alive() {
+            self.last_alive = Some(now);
+        }
+        self.status = PeerStatus::Idle;
+        self.socket = None;
+
+        Ok(())
+    }
+
+    /// How good the peer is, the higher the better: alive peers first, then peers seen alive
+    /// recently, while recent failures lower the score.
+    pub fn score(&self, now: DateTime<Utc>) -> i64 {
+        let status = match self.status {
+            PeerStatus::InAlive | PeerStatus::OutAlive => 3 * SCORE_UNIT,
+            PeerStatus::InHandshaking | PeerStatus::OutHandshaking => 2 * SCORE_UNIT,
+            PeerStatus::OutConnecting => SCORE_UNIT,
+            PeerStatus::Idle | PeerStatus::Banned => 0,
+        };
+        let alive = self.last_alive.map_or(0, |date| Self::recency(date, now));
+        let failure = self.last_failure.map_or(0, |date| Self::recency(date, now));
+
+        status + alive - failure
+    }
+
+    /// `SCORE_UNIT` for an event happening now, half of it for an event an hour ago.
+    fn recency(date: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+        let age = (now - date).num_seconds().max(0);
+        SCORE_UNIT * SCORE_HALF_LIFE_SECONDS / (SCORE_HALF_LIFE_SECONDS + age)
+    }
+
+    /// Exponential backoff after `failures` failures in a row, with a random part taken from
+    /// `jitter` so that the peers failing together are not dialed again together.
+    fn reconnect_delay(failures: u32, jitter: u64) -> chrono::Duration {
+        let delay = RECONNECT_DELAY_SECONDS
+            .saturating_mul(2i64.saturating_pow(failures.saturating_sub(1)))
+            .min(MAX_RECONNECT_DELAY_SECONDS);
+        // Between half and all of the delay
+        let shortening = (jitter % (delay as u64 / 2 + 1)) as i64;
+        chrono::Duration::seconds(delay - shortening)
+    }
+
+    /// The first failure or close reported after a superseded outgoing connection is the one of
+    /// that connection, it must not affect the connection that won.
+    pub fn consume_superseded_outgoing(&mut self) -> bool {
+        std::mem::take(&mut self.superseded_outgoing)
+    }
+
+    fn invalid_transition(&self, from: PeerStatus, to: PeerStatus) -> PeerError {
+        PeerError::InvalidTransition {
+            addr: self.addr,
+            from,
+            to,
+        }
+    }
+}
+
+/// Parse `ip:port`, or a bare `ip` listening on `default_port`.
+pub fn parse_addr(addr: &str, default_port: u16) -> Result<SocketAddr, AddrParseError> {
+    addr.parse::<SocketAddr>()
+        .or_else(|_| Ok(SocketAddr::new(addr.parse::<IpAddr>()?, default_port)))
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PeerStatus {
     Idle,
     OutConnecting,
@@ -58,3 +265,164 @@ pub enum PeerStatus {
     InAlive,
     Banned,
 }
+
+impl PeerStatus {
+    pub fn is_alive(&self) -> bool {
+        matches!(self, PeerStatus::InAlive | PeerStatus::OutAlive)
+    }
+
+    /// A TCP connection is open with the peer, whatever the direction.
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self,
+            PeerStatus::OutHandshaking
+                | PeerStatus::OutAlive
+                | PeerStatus::InHandshaking
+                | PeerStatus::InAlive
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::test_utils::now;
+
+    fn peer() -> Peer {
+        Peer::new("192.168.1.1:8080").expect("A valid peer")
+    }
+
+    #[test]
+    fn test_handshake_to_alive() {
+        let mut incoming = peer();
+        incoming.handshake(false);
+        incoming.alive(now()).expect("InHandshaking -> InAlive");
+        assert_eq!(PeerStatus::InAlive, incoming.status());
+        assert!(incoming.last_alive().is_some());
+
+        let mut outgoing = peer();
+        outgoing.handshake(true);
+        outgoing.alive(now()).expect("OutHandshaking -> OutAlive");
+        assert_eq!(PeerStatus::OutAlive, outgoing.status());
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        let mut peer = peer();
+        assert!(peer.alive(now()).is_err());
+        assert!(peer.failed(now(), 0).is_err());
+        assert!(peer.closed(now()).is_err());
+        assert_eq!(PeerStatus::Idle, peer.status());
+
+        peer.banned(now());
+        assert!(peer.alive(now()).is_err());
+        assert!(peer.closed(now()).is_err());
+        assert_eq!(PeerStatus::Banned, peer.status());
+
+        // The error names the alive status of the connection direction
+        peer.connecting();
+        assert!(matches!(
+            peer.alive(now()),
+            Err(PeerError::InvalidTransition {
+                from: PeerStatus::OutConnecting,
+                to: PeerStatus::OutAlive,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_failed_and_closed() {
+        let mut peer = peer();
+        peer.connecting();
+        peer.failed(now(), 0).expect("OutConnecting -> Idle");
+        assert_eq!(PeerStatus::Idle, peer.status());
+        assert!(peer.last_failure().is_some());
+        assert!(peer.last_alive().is_none());
+
+        peer.handshake(false);
+        peer.alive(now()).expect("InHandshaking -> InAlive");
+        peer.closed(now()).expect("InAlive -> Idle");
+        assert_eq!(PeerStatus::Idle, peer.status());
+    }
+
+    #[test]
+    fn test_superseded_outgoing() {
+        let mut peer = peer();
+        peer.handshake(true);
+        peer.supersede_outgoing();
+        assert_eq!(PeerStatus::InHandshaking, peer.status());
+
+        // The failure of the superseded connection is ignored
+        peer.failed(now(), 0)
+            .expect("Superseded connection failure");
+        assert_eq!(PeerStatus::InHandshaking, peer.status());
+        assert!(peer.last_failure().is_none());
+
+        peer.failed(now(), 0).expect("InHandshaking -> Idle");
+        assert_eq!(PeerStatus::Idle, peer.status());
+    }
+
+    #[test]
+    fn test_score() {
+        let now = now();
+        let untried = peer();
+        let mut alive = peer();
+        alive.handshake(true);
+        alive.alive(now).unwrap();
+        let mut seen_alive = peer();
+        seen_alive.last_alive = Some(now - chrono::Duration::hours(1));
+        let mut failed = peer();
+        failed.last_failure = Some(now - chrono::Duration::hours(1));
+        let mut failed_recently = peer();
+        failed_recently.last_failure = Some(now);
+
+        assert!(alive.score(now) > seen_alive.score(now));
+        assert!(seen_alive.score(now) > untried.score(now));
+        assert!(untried.score(now) > failed.score(now));
+        assert!(failed.score(now) > failed_recently.score(now));
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        assert_eq!(5, Peer::reconnect_delay(1, 0).num_seconds());
+        assert_eq!(20, Peer::reconnect_delay(3, 0).num_seconds());
+        assert_eq!(10, Peer::reconnect_delay(3, 10).num_seconds());
+        assert_eq!(
+            MAX_RECONNECT_DELAY_SECONDS,
+            Peer::reconnect_delay(40, 0).num_seconds()
+        );
+
+        let now = now();
+        let mut peer = peer();
+        for failures in 1..4 {
+            peer.connecting();
+            peer.failed(now, 10).unwrap();
+            assert_eq!(failures, peer.failures());
+        }
+        let next_dial = now + chrono::Duration::seconds(10);
+        assert_eq!(Some(next_dial), peer.next_dial());
+        assert!(!peer.can_dial(now));
+        assert!(peer.can_dial(next_dial));
+
+        // The backoff ends once the peer is alive again
+        peer.handshake(false);
+        peer.alive(now).unwrap();
+        assert_eq!(0, peer.failures());
+        assert!(peer.can_dial(now));
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(
+            Ok("192.168.1.1:8081".parse().unwrap()),
+            parse_addr("192.168.1.1:8081", 8080)
+        );
+        assert_eq!(
+            Ok("192.168.1.1:8080".parse().unwrap()),
+            parse_addr("192.168.1.1", 8080)
+        );
+        assert_eq!(Ok("[::1]:8080".parse().unwrap()), parse_addr("::1", 8080));
+        assert!(parse_addr("localhost", 8080).is_err());
+    }
+}
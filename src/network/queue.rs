@@ -0,0 +1,270 @@
+use crate::network::sync;
+use displaydoc::Display;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+#[derive(Display, Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The queue is full
+    Full,
+    /// The receiver is gone
+    Closed,
+}
+
+/// What happens to a new item when the queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// The new item is rejected: a new connection is closed, a new event is lost
+    #[default]
+    Reject,
+    /// The oldest item is dropped to make room for the new one
+    DropOldest,
+    /// Like `Reject`, but the acceptor waits for room before accepting more connections
+    Block,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "reject" => Ok(OverflowPolicy::Reject),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "block" => Ok(OverflowPolicy::Block),
+            _ => Err(format!("Unknown overflow policy {}", policy)),
+        }
+    }
+}
+
+/// Multi-producer multi-consumer queue holding at most `capacity` items, whose producers never
+/// wait: the overflow policy decides which item is lost when it is full.
+pub fn bounded<T>(capacity: usize, policy: OverflowPolicy) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            senders: 1,
+            receiver_closed: false,
+        }),
+        capacity,
+        policy,
+        dropped: AtomicU64::new(0),
+        pushed: Notify::new(),
+        popped: Notify::new(),
+    });
+
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    /// An item was pushed, or the last sender is gone
+    pushed: Notify,
+    /// An item was popped, or the receiver is gone
+    popped: Notify,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiver_closed: bool,
+}
+
+impl<T> Shared<T> {
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        sync::lock(&self.state)
+    }
+}
+
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueSender<T> {
+    /// Queue the item, and return the item dropped to make room for it, if any.
+    pub fn push(&self, item: T) -> Result<Option<T>, QueueError> {
+        let mut state = self.shared.state();
+        if state.receiver_closed {
+            return Err(QueueError::Closed);
+        }
+        let mut dropped = None;
+        if state.items.len() >= self.shared.capacity {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.shared.policy {
+                OverflowPolicy::DropOldest => dropped = state.items.pop_front(),
+                OverflowPolicy::Reject | OverflowPolicy::Block => return Err(QueueError::Full),
+            }
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.shared.pushed.notify_waiters();
+
+        Ok(dropped)
+    }
+
+    /// Queue the item even if the queue is full, for the rare items that must not be lost.
+    pub fn force_push(&self, item: T) -> Result<(), QueueError> {
+        let mut state = self.shared.state();
+        if state.receiver_closed {
+            return Err(QueueError::Closed);
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.shared.pushed.notify_waiters();
+
+        Ok(())
+    }
+
+    /// Wait until the queue has room, with the `Block` policy. Returns at once with the other
+    /// policies, which always make room or reject.
+    pub async fn ready(&self) {
+        if self.shared.policy != OverflowPolicy::Block {
+            return;
+        }
+        loop {
+            let popped = self.shared.popped.notified();
+            {
+                let state = self.shared.state();
+                if state.items.len() < self.shared.capacity || state.receiver_closed {
+                    return;
+                }
+            }
+            popped.await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state().receiver_closed
+    }
+
+    /// Number of items dropped or rejected because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state().senders += 1;
+        QueueSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.pushed.notify_waiters();
+        }
+    }
+}
+
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Next item, or `None` once the queue is empty and all the senders are gone.
+    pub async fn recv(&self) -> Option<T> {
+        loop {
+            let pushed = self.shared.pushed.notified();
+            {
+                let mut state = self.shared.state();
+                if let Some(item) = state.items.pop_front() {
+                    drop(state);
+                    self.shared.popped.notify_waiters();
+                    return Some(item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            pushed.await;
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let item = self.shared.state().items.pop_front();
+        if item.is_some() {
+            self.shared.popped.notify_waiters();
+        }
+        item
+    }
+
+    /// Number of items dropped or rejected because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        self.shared.state().receiver_closed = true;
+        self.shared.popped.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_overflow_policies() {
+        let (sender, receiver) = bounded(2, OverflowPolicy::Reject);
+        assert_eq!(Ok(None), sender.push(1));
+        assert_eq!(Ok(None), sender.push(2));
+        assert_eq!(Err(QueueError::Full), sender.push(3));
+        assert_eq!(Ok(()), sender.force_push(4));
+        assert_eq!(1, sender.dropped());
+        drop(sender);
+        assert_eq!(Some(1), receiver.recv().await);
+        assert_eq!(Some(2), receiver.recv().await);
+        assert_eq!(Some(4), receiver.recv().await);
+        assert_eq!(None, receiver.recv().await);
+
+        let (sender, receiver) = bounded(2, OverflowPolicy::DropOldest);
+        for item in 1..4 {
+            sender.push(item).unwrap();
+        }
+        assert_eq!(Ok(Some(2)), sender.push(4));
+        assert_eq!(2, sender.dropped());
+        assert_eq!(Some(3), receiver.try_recv());
+        drop(receiver);
+        assert_eq!(Err(QueueError::Closed), sender.push(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_block() {
+        let (sender, receiver) = bounded(1, OverflowPolicy::Block);
+        sender.push(1).unwrap();
+        assert_eq!(Err(QueueError::Full), sender.push(2));
+
+        let waiting = tokio::time::timeout(Duration::from_secs(1), sender.ready());
+        assert!(waiting.await.is_err());
+        let ready = tokio::spawn(async move {
+            sender.ready().await;
+            sender.push(3)
+        });
+        assert_eq!(Some(1), receiver.recv().await);
+        assert_eq!(Ok(None), ready.await.unwrap());
+        assert_eq!(Some(3), receiver.recv().await);
+    }
+}
@@ -0,0 +1,7 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Random number from the randomly seeded std hasher, good enough for nonces and jitter.
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
@@ -0,0 +1,7 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Lock the mutex even if a thread panicked while holding it. The network mutexes guard plain
+/// data that no code path leaves half updated, so the poison carries no information.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
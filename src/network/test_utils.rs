@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use tempfile::TempDir;
+
+pub fn now() -> DateTime<Utc> {
+    Utc::now()
+}
+
+pub fn addr(i: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, i], 8080))
+}
+
+/// Path of a file in its own temporary directory, removed with anything written next to it when
+/// dropped, even if the test fails first
+pub struct TempFile {
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// The file itself is not created.
+    pub fn new(name: &str) -> Self {
+        let dir = tempfile::tempdir().expect("A temporary directory");
+        let path = dir.path().join(name);
+        TempFile { _dir: dir, path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.path.to_str().expect("A UTF-8 temporary path")
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::network::sync;
+
+/// Buffer size of each direction of an in-memory connection, in bytes
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+/// First port given to the dialing side of in-memory connections
+const MEMORY_EPHEMERAL_PORT: u16 = 49152;
+
+/// Bidirectional byte stream between two nodes
+pub trait Connection: AsyncRead + AsyncWrite + Debug + Send + Sync + Unpin + 'static {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+pub type BoxedConnection = Box<dyn Connection>;
+
+/// Accepts the connections of a `Transport`
+pub trait Listener: Send + 'static {
+    /// Next incoming connection, with the address it comes from.
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedConnection, SocketAddr)>>;
+}
+
+/// How the controller listens for and dials connections: TCP by default, or in memory to run
+/// many nodes in one process.
+pub trait Transport: Send + Sync + 'static {
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>>;
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedConnection>>;
+}
+
+impl Connection for TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedConnection, SocketAddr)>> {
+        async move {
+            let (socket, addr) = TcpListener::accept(self).await?;
+            Ok((Box::new(socket) as BoxedConnection, addr))
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        async move { Ok(Box::new(TcpListener::bind(addr).await?) as Box<dyn Listener>) }.boxed()
+    }
+
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedConnection>> {
+        async move { Ok(Box::new(TcpStream::connect(addr).await?) as BoxedConnection) }.boxed()
+    }
+}
+
+type Incoming = mpsc::UnboundedSender<(BoxedConnection, SocketAddr)>;
+
+/// In-process network the `MemoryTransport`s dial each other through, without any socket.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    listeners: Arc<Mutex<HashMap<SocketAddr, Incoming>>>,
+    next_port: Arc<AtomicU16>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transport of a node of this network, whose connections come from `ip`.
+    pub fn transport(&self, ip: IpAddr) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            ip,
+        }
+    }
+
+    fn listeners(&self) -> MutexGuard<'_, HashMap<SocketAddr, Incoming>> {
+        sync::lock(&self.listeners)
+    }
+
+    fn ephemeral_port(&self) -> u16 {
+        let offset =
+            self.next_port.fetch_add(1, Ordering::Relaxed) % (u16::MAX - MEMORY_EPHEMERAL_PORT);
+        MEMORY_EPHEMERAL_PORT + offset
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    ip: IpAddr,
+}
+
+impl Transport for MemoryTransport {
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn Listener>>> {
+        let mut listeners = self.network.listeners();
+        let listener = match listeners.get(&addr) {
+            Some(incoming) if !incoming.is_closed() => Err(io::ErrorKind::AddrInUse.into()),
+            _ => {
+                let (incoming, receiver) = mpsc::unbounded_channel();
+                listeners.insert(addr, incoming);
+                Ok(Box::new(MemoryListener { receiver }) as Box<dyn Listener>)
+            }
+        };
+        futures::future::ready(listener).boxed()
+    }
+
+    fn dial(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedConnection>> {
+        let local_addr = SocketAddr::new(self.ip, self.network.ephemeral_port());
+        let (local, remote) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+        let remote = MemoryConnection {
+            stream: remote,
+            local_addr: addr,
+            peer_addr: local_addr,
+        };
+        let dialed = match self.network.listeners().get(&addr) {
+            Some(incoming) if incoming.send((Box::new(remote), local_addr)).is_ok() => {
+                Ok(Box::new(MemoryConnection {
+                    stream: local,
+                    local_addr,
+                    peer_addr: addr,
+                }) as BoxedConnection)
+            }
+            _ => Err(io::ErrorKind::ConnectionRefused.into()),
+        };
+        futures::future::ready(dialed).boxed()
+    }
+}
+
+struct MemoryListener {
+    receiver: mpsc::UnboundedReceiver<(BoxedConnection, SocketAddr)>,
+}
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxedConnection, SocketAddr)>> {
+        async move {
+            self.receiver
+                .recv()
+                .await
+                .ok_or_else(|| io::ErrorKind::NotConnected.into())
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct MemoryConnection {
+    stream: DuplexStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl Connection for MemoryConnection {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+}
+
+impl AsyncRead for MemoryConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let network = MemoryNetwork::new();
+        let server_addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let server = network.transport(server_addr.ip());
+        let client = network.transport("10.0.0.2".parse().unwrap());
+
+        let mut listener = server.listen(server_addr).await.unwrap();
+        assert_eq!(
+            io::ErrorKind::AddrInUse,
+            server.listen(server_addr).await.err().unwrap().kind()
+        );
+        let mut dialed = client.dial(server_addr).await.unwrap();
+        let (mut accepted, from) = listener.accept().await.unwrap();
+        assert_eq!(dialed.local_addr().unwrap(), from);
+        assert_eq!(server_addr, accepted.local_addr().unwrap());
+        assert_eq!(server_addr, dialed.peer_addr().unwrap());
+
+        dialed.write_all(b"ping").await.unwrap();
+        let mut buffer = [0u8; 4];
+        accepted.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"ping", &buffer);
+
+        // Nobody listens once the listener is dropped
+        drop(listener);
+        assert_eq!(
+            io::ErrorKind::ConnectionRefused,
+            client.dial(server_addr).await.err().unwrap().kind()
+        );
+        assert!(server.listen(server_addr).await.is_ok());
+    }
+}
@@ -0,0 +1,260 @@
+//! End to end scenarios running several controllers in one process over the in-memory
+//! transport. Each node runs a small application loop that keeps the connections it is handed,
+//! closes the ones of the peers it bans, and reports broken connections to its controller.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::StreamExt;
+use tempfile::TempDir;
+use test_massa::codec::{self, MAX_FRAME_SIZE};
+use test_massa::{
+    BoxedConnection, ChannelMessage, ConnectionRejection, EventKind, MemoryNetwork, MockClock,
+    NetworkConfig, NetworkController, NetworkControllerEvent, NetworkEvent, SharedClock,
+};
+use tokio::task::JoinHandle;
+
+/// How often the conditions of `Simulation::wait_until` are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the application loop of a node saw
+#[derive(Default)]
+struct NodeState {
+    alive: HashSet<SocketAddr>,
+    rejected: Vec<(SocketAddr, ConnectionRejection)>,
+}
+
+struct Node {
+    addr: SocketAddr,
+    net: NetworkController,
+    state: Arc<Mutex<NodeState>>,
+    driver: JoinHandle<()>,
+}
+
+impl Node {
+    fn state(&self) -> MutexGuard<'_, NodeState> {
+        self.state.lock().unwrap()
+    }
+}
+
+struct Simulation {
+    nodes: Vec<Node>,
+    /// Holds the peers files, removed when the simulation is dropped
+    peers_dir: TempDir,
+}
+
+impl Simulation {
+    /// Start a node at 10.0.0.i:8080 for each bootstrap list, where `bootstrap[i]` holds the
+    /// indexes of the nodes node `i` knows at startup.
+    async fn start(bootstrap: &[&[usize]]) -> Self {
+        let network = MemoryNetwork::new();
+        // Dates follow the paused tokio time, so that the reconnect backoffs run out
+        let clock: SharedClock = Arc::new(MockClock::new(Utc::now()));
+        let mut simulation = Simulation {
+            nodes: Vec::new(),
+            peers_dir: tempfile::tempdir().unwrap(),
+        };
+        for (i, known) in bootstrap.iter().enumerate() {
+            let addr = Self::addr(i);
+            let known: Vec<SocketAddr> = known.iter().map(|&j| Self::addr(j)).collect();
+            let peers_file = simulation.peers_dir.path().join(format!("peers-{i}.json"));
+            std::fs::write(&peers_file, serde_json::to_string(&known).unwrap()).unwrap();
+            let config = NetworkConfig::builder()
+                .peers_file(peers_file.to_str().unwrap())
+                .listen_ip(addr.ip())
+                .listen_port(addr.port())
+                .build()
+                .unwrap();
+            let net = NetworkController::builder(config)
+                .transport(Arc::new(network.transport(addr.ip())))
+                .clock(clock.clone())
+                .start()
+                .await
+                .unwrap();
+
+            let state = Arc::new(Mutex::new(NodeState::default()));
+            let driver = tokio::spawn(drive(net.clone(), state.clone()));
+            simulation.nodes.push(Node {
+                addr,
+                net,
+                state,
+                driver,
+            });
+        }
+
+        simulation
+    }
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i as u8 + 1], 8080))
+    }
+
+    /// Wait until the condition holds, failing after `timeout`.
+    async fn wait_until(&self, timeout: Duration, what: &str, condition: impl Fn(&Self) -> bool) {
+        let reached = tokio::time::timeout(timeout, async {
+            while !condition(self) {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+        assert!(
+            reached.await.is_ok(),
+            "{what} not reached within {timeout:?}"
+        );
+    }
+
+    /// Check that the condition holds during all of `duration`.
+    async fn assert_during(
+        &self,
+        duration: Duration,
+        what: &str,
+        condition: impl Fn(&Self) -> bool,
+    ) {
+        let deadline = tokio::time::Instant::now() + duration;
+        while tokio::time::Instant::now() < deadline {
+            assert!(condition(self), "{what} broken");
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait until every node has at least `count` alive peers.
+    async fn wait_alive_peers(&self, count: usize, timeout: Duration) {
+        self.wait_until(
+            timeout,
+            &format!("{count} alive peers per node"),
+            |simulation| {
+                simulation
+                    .nodes
+                    .iter()
+                    .all(|node| node.state().alive.len() >= count)
+            },
+        )
+        .await;
+    }
+
+    fn is_alive(&self, node: usize, peer: usize) -> bool {
+        self.nodes[node].state().alive.contains(&Self::addr(peer))
+    }
+
+    fn rejected_banned(&self, node: usize, peer: usize) -> bool {
+        let ip = Self::addr(peer).ip();
+        self.nodes[node]
+            .state()
+            .rejected
+            .iter()
+            .any(|(addr, reason)| addr.ip() == ip && *reason == ConnectionRejection::Banned)
+    }
+
+    async fn shutdown(self) {
+        for node in self.nodes {
+            node.driver.abort();
+            node.net.shutdown().await.unwrap();
+        }
+    }
+}
+
+/// Application loop of a node: serve the connections handed over by the controller, and keep
+/// track of the alive peers and of the rejected connections.
+async fn drive(net: NetworkController, state: Arc<Mutex<NodeState>>) {
+    while let Ok(event) = net.wait_event().await {
+        let mut state = state.lock().unwrap();
+        match event {
+            NetworkControllerEvent::CandidateConnection { addr, socket, .. } => {
+                tokio::spawn(serve(net.clone(), addr, socket));
+            }
+            NetworkControllerEvent::Event(NetworkEvent::PeerAlive { addr, .. }) => {
+                state.alive.insert(addr);
+            }
+            NetworkControllerEvent::Event(
+                NetworkEvent::PeerClosed { addr }
+                | NetworkEvent::PeerFailed { addr }
+                | NetworkEvent::PeerBanned { addr },
+            ) => {
+                state.alive.remove(&addr);
+            }
+            NetworkControllerEvent::Event(NetworkEvent::ConnectionRejected { addr, reason }) => {
+                state.rejected.push((addr, reason));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Read the connection until it breaks or its peer is banned, then tell the controller.
+async fn serve(net: NetworkController, addr: SocketAddr, mut socket: BoxedConnection) {
+    let Ok(mut bans) = net.subscribe(Some(vec![EventKind::PeerBanned])).await else {
+        return;
+    };
+    let banned = async {
+        while let Some(event) = bans.next().await {
+            if event == Ok(NetworkEvent::PeerBanned { addr }) {
+                return;
+            }
+        }
+        futures::future::pending().await
+    };
+    let closed = async {
+        loop {
+            match codec::read_message(&mut socket, MAX_FRAME_SIZE).await {
+                Ok(ChannelMessage::Close) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    };
+
+    tokio::select! {
+        // Dropping the socket closes the connection
+        _ = banned => {}
+        closed = closed => {
+            let _ = match closed {
+                true => net.feedback_peer_closed(&addr).await,
+                false => net.feedback_peer_failed(&addr).await,
+            };
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_ring_reaches_alive_peers() {
+    // Each node knows the next one, and is dialed by the previous one
+    let simulation = Simulation::start(&[&[1], &[2], &[3], &[4], &[0]]).await;
+
+    simulation
+        .wait_alive_peers(2, Duration::from_secs(10))
+        .await;
+    assert!(simulation.is_alive(0, 1) && simulation.is_alive(0, 4));
+
+    simulation.shutdown().await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_banned_node_cannot_reconnect() {
+    let simulation = Simulation::start(&[&[], &[0]]).await;
+    simulation
+        .wait_alive_peers(1, Duration::from_secs(10))
+        .await;
+
+    simulation.nodes[0]
+        .net
+        .feedback_peer_banned(&simulation.nodes[1].addr)
+        .await
+        .unwrap();
+    // Node 1 sees its connection break and dials again, but node 0 turns it away
+    simulation
+        .wait_until(
+            Duration::from_secs(10),
+            "rejection of the banned node",
+            |simulation| simulation.rejected_banned(0, 1),
+        )
+        .await;
+    simulation
+        .assert_during(Duration::from_secs(10), "ban of node 1", |simulation| {
+            !simulation.is_alive(0, 1) && !simulation.is_alive(1, 0)
+        })
+        .await;
+
+    simulation.shutdown().await;
+}